@@ -3,18 +3,65 @@ extern crate nix;
 use std::io;
 use std::io::prelude::*;
 use std::io::Write;
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::env;
 use std::env::args;
-use std::path::Path;
+use std::process;
+use std::path::{Path, PathBuf};
 use std::cmp;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::{self, EventFlags, PollFd};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::sys::termios;
 
+/// Set by the SIGWINCH handler when the terminal was resized, so that the
+/// window size is queried again before the next redraw.
+static RESIZE_NEEDED: AtomicBool = AtomicBool::new(false);
+
+/// The most marks kept in the mark stack, after which the oldest is dropped.
+const MARK_STACK_SIZE: usize = 16;
+/// The most killed texts kept in the kill ring, after which the oldest is
+/// dropped.
+const KILL_RING_SIZE: usize = 16;
+
 pub struct Config {
     tab_width: i32,
+    // If set, the part of each line beyond this many columns is highlighted
+    // to warn about overly long lines.
+    max_line_length: Option<usize>,
+    // What else to do about lines longer than `max_line_length`.
+    max_line_length_action: MaxLineLengthAction,
+    // Whether to copy a file to `<path>~` before it's first overwritten.
+    auto_backup: bool,
+}
+
+/// What to do about lines longer than the configured maximum, on top of
+/// highlighting the excess.
+#[derive(Clone, Copy, PartialEq)]
+enum MaxLineLengthAction {
+    /// Only highlight them.
+    Warn,
+    /// Break them into lines that fit, at the last space if possible, when the
+    /// file is saved.
+    HardWrap,
+}
+
+impl MaxLineLengthAction {
+    fn from_name(name: &str) -> Option<MaxLineLengthAction> {
+        match name {
+            "warn" => Some(MaxLineLengthAction::Warn),
+            "hard_wrap" => Some(MaxLineLengthAction::HardWrap),
+            _ => None,
+        }
+    }
 }
 
 /// A data type that represents where in the console window something resides.
@@ -37,16 +84,40 @@ enum Key {
     PageDown,
     LineHome,
     LineEnd,
-    FileHome,
+    FileStart,
     FileEnd,
     Delete,
+    Backspace,
+    Enter,
+    WindowTop,
+    WindowMiddle,
+    WindowBottom,
+    HalfPageDown,
+    HalfPageUp,
+    SaveAs,
+    Redo,
+    CountWords,
+    EvalRegion,
+    // The function keys F1 to F12.
+    F(u8),
+    WordRight,
+    WordLeft,
+    // The Escape key on its own, not starting an escape sequence.
+    Escape,
+}
+
+/// The direction in which a search looks for the next match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SearchDirection {
+    Forward,
+    Backward,
 }
 
 fn ctrl_mask(c: char) -> char {
     (c as u8 & 0x1f) as char
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Cursor {
     /// The position of the cursor in the terminal window.
     pos: Pos,
@@ -61,8 +132,11 @@ struct Cursor {
     /// In order to be able to go up and down along the ends of lines of
     /// different lengths (including 0), this flag needs to be set to determine
     /// whether to go to the same column in the next row or to its end.
-    // TODO don't limit to EoL: make it universal, as in with a `stay_on_col` field
     is_at_eol: bool,
+    /// The column the cursor was last put in by horizontal movement. Vertical
+    /// movement tries to land on this column so that passing through a shorter
+    /// row does not make the cursor forget where it came from.
+    desired_col: usize,
 }
 
 struct Line {
@@ -77,8 +151,27 @@ impl Line {
         self.render.len()
     }
 
-    fn is_empty(&self) -> bool {
-        self.render.is_empty()
+    /// Returns the number of characters in the rendered line, each of which
+    /// takes up one column on screen.
+    fn n_chars(&self) -> usize {
+        self.char_index(self.len())
+    }
+
+    /// Returns the index of the character that starts at offset `byte` in the
+    /// rendered line, i.e. the number of characters before it.
+    fn char_index(&self, byte: usize) -> usize {
+        self.render[..byte].iter().filter(|b| *b & 0xc0 != 0x80).count()
+    }
+
+    /// The inverse of `char_index`: returns the offset of the first byte of
+    /// the character at index `char_index`, or the line's length if there are
+    /// not that many characters.
+    fn char_byte(&self, char_index: usize) -> usize {
+        self.render.iter()
+            .enumerate()
+            .filter(|&(_, b)| *b & 0xc0 != 0x80)
+            .nth(char_index)
+            .map_or(self.len(), |(byte, _)| byte)
     }
 }
 
@@ -90,6 +183,215 @@ struct StatusMsg {
     timeout: Duration,
 }
 
+/// A modification of the text that can be undone. Offsets into lines are
+/// into their original bytes, so that they're not affected by tabs.
+trait Command {
+    fn apply(&self, editor: &mut Editor);
+    fn unapply(&self, editor: &mut Editor);
+}
+
+/// Inserts `bytes` into the line at index `line` at offset `at`.
+struct InsertChar {
+    line: usize,
+    at: usize,
+    bytes: Vec<u8>,
+}
+
+impl Command for InsertChar {
+    fn apply(&self, editor: &mut Editor) {
+        editor.lines[self.line].orig.splice(self.at..self.at, self.bytes.iter().cloned());
+        editor.update_render(self.line);
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        editor.lines[self.line].orig.drain(self.at..self.at + self.bytes.len());
+        editor.update_render(self.line);
+    }
+}
+
+/// Deletes `bytes`, which start at offset `at`, from the line at index `line`.
+struct DeleteRange {
+    line: usize,
+    at: usize,
+    bytes: Vec<u8>,
+}
+
+impl Command for DeleteRange {
+    fn apply(&self, editor: &mut Editor) {
+        editor.lines[self.line].orig.drain(self.at..self.at + self.bytes.len());
+        editor.update_render(self.line);
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        editor.lines[self.line].orig.splice(self.at..self.at, self.bytes.iter().cloned());
+        editor.update_render(self.line);
+    }
+}
+
+/// Splits the line at index `line` in two at offset `at`.
+struct SplitLine {
+    line: usize,
+    at: usize,
+}
+
+impl Command for SplitLine {
+    fn apply(&self, editor: &mut Editor) {
+        editor.split_line(self.line, self.at);
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        editor.join_next_line(self.line);
+    }
+}
+
+/// Appends the line after the line at index `line` to it. `at` is the length
+/// of the latter before the merge, where it's split again when undone.
+struct MergeLines {
+    line: usize,
+    at: usize,
+}
+
+impl Command for MergeLines {
+    fn apply(&self, editor: &mut Editor) {
+        editor.join_next_line(self.line);
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        editor.split_line(self.line, self.at);
+    }
+}
+
+/// Inserts a copy of the line at index `line` after it.
+struct DuplicateLine {
+    line: usize,
+}
+
+impl Command for DuplicateLine {
+    fn apply(&self, editor: &mut Editor) {
+        let orig = editor.lines[self.line].orig.clone();
+        let render = editor.lines[self.line].render.clone();
+        editor.lines.insert(self.line + 1, Line { orig: orig, render: render });
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        editor.lines.remove(self.line + 1);
+    }
+}
+
+/// Removes the line at index `line`, whose original bytes are `orig`. If it's
+/// the only line, it's emptied instead so that there is always a line.
+struct DeletedLine {
+    line: usize,
+    orig: Vec<u8>,
+    is_only_line: bool,
+}
+
+impl Command for DeletedLine {
+    fn apply(&self, editor: &mut Editor) {
+        if self.is_only_line {
+            editor.lines[self.line].orig.clear();
+            editor.update_render(self.line);
+        } else {
+            editor.lines.remove(self.line);
+        }
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        if self.is_only_line {
+            editor.lines[self.line].orig = self.orig.clone();
+            editor.update_render(self.line);
+        } else {
+            let render = editor.line_orig_to_render(&self.orig);
+            editor.lines.insert(self.line, Line { orig: self.orig.clone(), render: render });
+        }
+    }
+}
+
+/// Inserts `text`, which may span several lines, into the line at index
+/// `line` at offset `at`.
+struct InsertText {
+    line: usize,
+    at: usize,
+    text: Vec<u8>,
+}
+
+impl Command for InsertText {
+    fn apply(&self, editor: &mut Editor) {
+        let rest = editor.lines[self.line].orig.split_off(self.at);
+        let mut line = self.line;
+        for (i, part) in self.text.split(|b| *b == b'\n').enumerate() {
+            if i > 0 {
+                line += 1;
+                editor.lines.insert(line, Line { orig: vec![], render: vec![] });
+            }
+            editor.lines[line].orig.extend_from_slice(part);
+        }
+        editor.lines[line].orig.extend(rest);
+        for line in self.line..line + 1 {
+            editor.update_render(line);
+        }
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        let (last_line, last_at) = self.end();
+        let rest = editor.lines[last_line].orig.split_off(last_at);
+        editor.lines.drain(self.line + 1..last_line + 1);
+        editor.lines[self.line].orig.truncate(self.at);
+        editor.lines[self.line].orig.extend(rest);
+        editor.update_render(self.line);
+    }
+}
+
+impl InsertText {
+    /// Returns the line and the offset into it where the inserted text ends.
+    fn end(&self) -> (usize, usize) {
+        let n_newlines = self.text.iter().filter(|b| **b == b'\n').count();
+        let last_part_len = self.text.split(|b| *b == b'\n').last().map_or(0, |part| part.len());
+        if n_newlines == 0 {
+            (self.line, self.at + last_part_len)
+        } else {
+            (self.line + n_newlines, last_part_len)
+        }
+    }
+}
+
+/// Replaces the lines starting at index `first` whose original bytes are `old`
+/// with `new`, which need not be as many lines.
+struct ReplaceLines {
+    first: usize,
+    old: Vec<Vec<u8>>,
+    new: Vec<Vec<u8>>,
+}
+
+impl ReplaceLines {
+    fn replace(editor: &mut Editor, first: usize, n_replaced: usize, lines: &[Vec<u8>]) {
+        let lines: Vec<Line> = lines.iter()
+            .map(|orig| Line { orig: orig.clone(), render: editor.line_orig_to_render(orig) })
+            .collect();
+        editor.lines.splice(first..first + n_replaced, lines);
+    }
+}
+
+impl Command for ReplaceLines {
+    fn apply(&self, editor: &mut Editor) {
+        ReplaceLines::replace(editor, self.first, self.old.len(), &self.new);
+    }
+
+    fn unapply(&self, editor: &mut Editor) {
+        ReplaceLines::replace(editor, self.first, self.new.len(), &self.old);
+    }
+}
+
+/// A command in the undo or redo stack, along with where the cursor was
+/// before and after it was applied, so that undoing and redoing it restore
+/// the cursor as well.
+struct Edit {
+    command: Box<dyn Command>,
+    // The cursor's line and byte, as in `Cursor`.
+    cursor_before: (usize, usize),
+    cursor_after: (usize, usize),
+}
+
 struct Editor {
     // Note that this does not always report the actual position of the cursor.
     // Instead, it reflects the _desired_ position, i.e. what user sets. It may
@@ -109,21 +411,66 @@ struct Editor {
     lines: Vec<Line>,
     // The zero-based index into `lines` of the first line to show.
     line_offset: usize,
-    // The first character of the row in line that should be drawn. Always
-    // a multiple of `window_width`. Also zero-based.
+    // The offset of the first byte of the row in line that should be drawn.
+    // Always that of a character whose index is a multiple of `window_width`.
     line_offset_byte: usize,
     config: Config,
-    // The path of the file currently being edited. Stored as a string since
-    // we're only printing it on the status bar.
-    path: String,
+    // The path of the file currently being edited, or None if the text is not
+    // backed by a file (e.g. the scratch buffer).
+    path: Option<PathBuf>,
+    // Whether the last line is terminated by a new-line character, so that
+    // saving the file doesn't add or remove one. None if the file was empty,
+    // in which case the last line is terminated only if there is any text.
+    trailing_newline: Option<bool>,
+    // Whether the backup of the file has been written in this session, after
+    // which it's not to be overwritten with an already modified version.
+    backup_written: bool,
     // Store the status message so that it's persisted across screen redraws.
     status_msg: StatusMsg,
+    // The depth of the undo stack when the text was last loaded or saved, so
+    // that undoing back to that point leaves the text unmodified. None if it
+    // no longer matches the text at any depth.
+    saved_undo_depth: Option<usize>,
+    // Whether modifying the text is disallowed.
+    read_only: bool,
+    // The modifications made to the text, the most recent one last.
+    undo_stack: Vec<Edit>,
+    // The modifications undone since the last new one, the most recently
+    // undone one last.
+    redo_stack: Vec<Edit>,
+    // Text removed by kill commands, to be inserted again by yanking. The
+    // most recent kill is the last one.
+    kill_ring: Vec<Vec<u8>>,
+    // Cursor positions, as (line, byte) pairs, that the user marked to be
+    // able to return to them later. The most recent mark is the last one.
+    mark_stack: Vec<(usize, usize)>,
+    // Set when the user tried to quit with unsaved changes, in which case
+    // quitting again right away goes through.
+    pending_quit: bool,
+    // The line the cursor last jumped to and when, which is highlighted for
+    // a moment so that the user can find the cursor.
+    pulse_line: Option<(usize, Instant)>,
+    // The match of the ongoing search as the line and the range of its
+    // rendered bytes, which is highlighted.
+    search_match: Option<(usize, usize, usize)>,
+    // The query of the last search, which a new search starts out with.
+    search_query: String,
+    search_direction: SearchDirection,
+    // Whether the last key was Ctrl+U and if so, whether it deleted any text,
+    // which is taken back if Ctrl+Space follows.
+    after_ctrl_u: Option<bool>,
 }
 
 impl Editor {
-    fn new(config: Config, path: String) -> Editor {
+    fn new(config: Config, path: Option<PathBuf>) -> Editor {
         Editor {
-            cursor: Cursor { pos: Pos { row: 0, col: 0 }, line: 0, byte: 0, is_at_eol: false },
+            cursor: Cursor {
+                pos: Pos { row: 0, col: 0 },
+                line: 0,
+                byte: 0,
+                is_at_eol: false,
+                desired_col: 0,
+            },
             window_width: 0,
             window_height: 0,
             write_buf: vec![],
@@ -132,26 +479,82 @@ impl Editor {
             line_offset_byte: 0,
             config: config,
             path: path,
+            trailing_newline: Some(true),
+            backup_written: false,
             status_msg: StatusMsg {
                 data: String::new(),
                 timestamp: Instant::now(),
                 timeout: Duration::new(0, 0),
             },
+            saved_undo_depth: Some(0),
+            read_only: false,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            kill_ring: Vec::new(),
+            mark_stack: Vec::new(),
+            pending_quit: false,
+            pulse_line: None,
+            search_match: None,
+            search_query: String::new(),
+            search_direction: SearchDirection::Forward,
+            after_ctrl_u: None,
         }
     }
 
+    /// Creates an editor for a `*scratch*` buffer: a single empty line that
+    /// is not backed by any file, meant for temporary notes.
+    pub fn new_scratch(config: Config) -> Editor {
+        let mut editor = Editor::new(config, None);
+        editor.lines.push(Line { orig: vec![], render: vec![] });
+        editor
+    }
+
     pub fn open_file(config: Config, path: &Path) -> std::io::Result<Editor> {
-        let mut file = File::open(path)?;
-        let path = path.file_name().unwrap().to_str().unwrap().to_string();
-        let mut editor = Editor::new(config, path);
+        let comment_prefix = line_comment_prefix(path);
+        let mut editor = Editor::new(config, Some(path.to_path_buf()));
         let mut buf = vec![];
 
-        file.read_to_end(&mut buf).unwrap();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut buf)?;
+            }
+            // A file that doesn't exist yet starts out empty and is created
+            // on the first save.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        // A newly created file starts out with the template for its type, if
+        // the user has one.
+        if buf.is_empty() {
+            if let Some(template) = read_template(path) {
+                buf = template;
+                // The template is not in the file yet.
+                editor.saved_undo_depth = None;
+            }
+        }
+
+        // The new-line terminating the last line would otherwise produce an
+        // extra empty line, so it's stripped here and added back on save. An
+        // empty file has no last line to go by.
+        editor.trailing_newline = buf.last().map(|b| *b == b'\n');
+        if editor.trailing_newline == Some(true) {
+            buf.pop();
+        }
 
         // TODO might need to match \r\n as well
-        // FIXME there's an extra empty space at the end even if there shouldn't be
         let lines = buf.split(|b| *b == '\n' as u8);
 
+        // File-local settings may affect how lines are rendered (e.g. the tab
+        // width), so they must be applied before the lines are processed.
+        if let Some(comment_prefix) = comment_prefix {
+            let first_line = lines.clone().next();
+            let last_line = lines.clone().rev().find(|line| !line.is_empty());
+            for line in first_line.iter().chain(last_line.iter()) {
+                editor.apply_file_local_vars(line, comment_prefix);
+            }
+        }
+
         // Try to get an esimate of the number of lines in file.
         let size_hint = {
             let (lower, upper) = lines.size_hint();
@@ -177,18 +580,405 @@ impl Editor {
         Ok(editor)
     }
 
+    /// Applies the settings in `line` if it's a comment of the form
+    /// `<comment_prefix> kilo: KEY=VALUE KEY=VALUE ...`, overriding the global
+    /// configuration for this file. Unknown keys and invalid values are ignored.
+    fn apply_file_local_vars(&mut self, line: &[u8], comment_prefix: &str) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if !line.starts_with(comment_prefix) {
+            return;
+        }
+        let line = line[comment_prefix.len()..].trim_start();
+        if !line.starts_with("kilo:") {
+            return;
+        }
+
+        for var in line["kilo:".len()..].split_whitespace() {
+            let mut parts = var.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => continue,
+            };
+            match key {
+                "tabstop" | "tab_width" => {
+                    if let Ok(tab_width) = value.parse() {
+                        if tab_width > 0 {
+                            self.config.tab_width = tab_width;
+                        }
+                    }
+                }
+                "max_line_length" => {
+                    if let Ok(max_line_length) = value.parse() {
+                        self.config.max_line_length = Some(max_line_length);
+                    }
+                }
+                "max_line_length_action" => {
+                    if let Some(action) = MaxLineLengthAction::from_name(value) {
+                        self.config.max_line_length_action = action;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Returns the name of the file being edited, as shown to the user.
+    fn file_name(&self) -> String {
+        match self.path {
+            Some(ref path) => path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.to_string_lossy().into_owned()),
+            None => "*scratch*".to_string(),
+        }
+    }
+
+    /// Saves the text to the file it was opened from and reports the outcome
+    /// in the message bar. If there is no such file yet, the user is asked
+    /// for a path instead.
+    fn save(&mut self) {
+        if self.path.is_none() {
+            return self.save_as();
+        }
+
+        if self.config.max_line_length_action == MaxLineLengthAction::HardWrap {
+            self.hard_wrap_long_lines();
+        }
+
+        let mut backup = None;
+        if self.config.auto_backup && !self.backup_written {
+            backup = Some(self.write_backup());
+            // Even if it failed, a later backup would only be of the text as
+            // modified in this session.
+            self.backup_written = true;
+        }
+
+        match self.write_file() {
+            Ok(n_bytes) => {
+                let n_lines = self.lines.len();
+                let mut msg = format!("\"{}\" {} {}, {} bytes written", self.file_name(),
+                                      n_lines, if n_lines == 1 { "line" } else { "lines" }, n_bytes);
+                match backup {
+                    Some(Ok(Some(backup_path))) => {
+                        msg += &format!(" (backup: {})", backup_path.display());
+                    }
+                    // The file may be writable while its directory isn't,
+                    // which doesn't stop saving it.
+                    Some(Err(e)) => msg += &format!(" (can't write backup: {})", e),
+                    _ => (),
+                }
+                self.new_status_msg(&msg, Duration::from_secs(2));
+            }
+            Err(e) => {
+                let msg = format!("Can't save: {}", e);
+                self.set_message(&msg);
+            }
+        }
+    }
+
+    /// Breaks the lines longer than `max_line_length` into lines that fit, as a
+    /// single edit that can be undone. The cursor stays on the same character.
+    fn hard_wrap_long_lines(&mut self) {
+        let max_len = match self.config.max_line_length {
+            Some(max_len) if max_len > 0 => max_len,
+            _ => return,
+        };
+        let is_long = |line: &Line| line.n_chars() > max_len;
+        let first = match self.lines.iter().position(&is_long) {
+            Some(first) => first,
+            None => return,
+        };
+        let last = self.lines.iter().rposition(&is_long).unwrap();
+
+        let cursor_line = self.cursor.line;
+        let cursor_at = self.render_to_orig_byte(&self.lines[cursor_line], self.cursor.byte);
+        let mut cursor_after = (cursor_line, cursor_at);
+        let mut old = vec![];
+        let mut new = vec![];
+        for line in first..last + 1 {
+            let orig = self.lines[line].orig.clone();
+            let parts = self.hard_wrap(&orig, max_len);
+            if line == cursor_line {
+                // The cursor goes into the last part starting at or before it.
+                let i = parts.iter().rposition(|&(start, _)| start <= cursor_at).unwrap_or(0);
+                let (start, ref part) = parts[i];
+                let at = cmp::min(cursor_at - start, part.len());
+                cursor_after = (first + new.len() + i, at);
+            }
+            new.extend(parts.into_iter().map(|(_, part)| part));
+            old.push(orig);
+        }
+        if cursor_line > last {
+            cursor_after.0 += new.len() - old.len();
+        }
+        self.execute(ReplaceLines { first: first, old: old, new: new }, cursor_after);
+    }
+
+    /// Splits `orig`, the original bytes of a line, into parts that are at most
+    /// `max_len` columns wide, returning each along with its offset in `orig`.
+    /// A line is broken at its last space that fits, which is dropped, or
+    /// right at the limit if there is none.
+    fn hard_wrap(&self, orig: &[u8], max_len: usize) -> Vec<(usize, Vec<u8>)> {
+        let mut parts = vec![];
+        let mut start = 0;
+        loop {
+            let rest = &orig[start..];
+            let rest = Line { orig: rest.to_vec(), render: self.line_orig_to_render(rest) };
+            if rest.n_chars() <= max_len {
+                parts.push((start, rest.orig));
+                return parts;
+            }
+            // The first byte that doesn't fit. A tab or character that is
+            // wider than the limit is kept whole so that each part has some
+            // text.
+            let mut limit = self.render_to_orig_byte(&rest, rest.char_byte(max_len));
+            while limit == 0 || (limit < rest.orig.len() && rest.orig[limit] & 0xc0 == 0x80) {
+                limit += 1;
+            }
+            // A space right at the limit may be dropped as well.
+            let n_fitting = cmp::min(limit + 1, rest.orig.len());
+            let (end, next_start) = match rest.orig[..n_fitting].iter().rposition(|b| *b == b' ') {
+                Some(space) if rest.orig[..space].iter().any(|b| *b != b' ') => {
+                    let n_spaces = rest.orig[..space].iter().rev().take_while(|b| **b == b' ').count();
+                    (space - n_spaces, space + 1)
+                }
+                _ => (limit, limit),
+            };
+            parts.push((start, rest.orig[..end].to_vec()));
+            if next_start == rest.orig.len() {
+                // Only the dropped space was left.
+                return parts;
+            }
+            start += next_start;
+        }
+    }
+
+    /// Copies the file being edited to `<path>~`, returning the path of the
+    /// backup, or None if the file doesn't exist yet.
+    fn write_backup(&self) -> io::Result<Option<PathBuf>> {
+        let path = match self.path {
+            Some(ref path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let mut backup_path = path.clone().into_os_string();
+        backup_path.push("~");
+        let backup_path = PathBuf::from(backup_path);
+        fs::copy(path, &backup_path)?;
+        Ok(Some(backup_path))
+    }
+
+    /// Asks the user for a new path to save the text to, which is used for
+    /// all subsequent saves as well.
+    fn save_as(&mut self) {
+        if let Some(path) = self.prompt("Save as: ") {
+            self.path = Some(PathBuf::from(path));
+            // The new file, if it exists, has not been backed up yet.
+            self.backup_written = false;
+            self.save();
+        }
+    }
+
+    /// Switches between allowing and disallowing modifying the text.
+    fn toggle_read_only(&mut self) {
+        if self.read_only {
+            self.read_only = false;
+            // The file may still not be writable, in which case saving fails.
+            let is_writable = match self.path {
+                Some(ref path) if path.exists() => {
+                    OpenOptions::new().write(true).open(path).is_ok()
+                }
+                _ => true,
+            };
+            if is_writable {
+                self.new_status_msg("Buffer is writable", Duration::from_secs(2));
+            } else {
+                self.set_message("Warning: file is not writable on disk");
+            }
+        } else {
+            if self.is_dirty() {
+                let answer = self.prompt("Buffer has unsaved changes. Make it read-only anyway? (y/n) ");
+                if answer.map_or(true, |answer| !answer.starts_with('y')) {
+                    return;
+                }
+            }
+            self.read_only = true;
+            self.new_status_msg("Buffer is read-only", Duration::from_secs(2));
+        }
+    }
+
+    /// Asks the user for a file and appends the whole text to it, creating
+    /// the file if it doesn't exist.
+    fn append_to_file(&mut self) {
+        let path = match self.prompt("Append to file: ") {
+            Some(path) => path,
+            None => return,
+        };
+        let result = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .and_then(|file| {
+                let mut writer = io::BufWriter::new(file);
+                self.write_lines(&mut writer)?;
+                writer.flush()
+            });
+        match result {
+            Ok(()) => {
+                let msg = format!("Appended {} lines to {}", self.lines.len(), path);
+                self.new_status_msg(&msg, Duration::from_secs(2));
+            }
+            Err(e) => {
+                let msg = format!("Can't append to {}: {}", path, e);
+                self.set_message(&msg);
+            }
+        }
+    }
+
+    /// Writes all lines to `path`, returning the number of bytes written.
+    fn write_file(&mut self) -> io::Result<usize> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "no file name")),
+        };
+        // Saving through a symlink writes to the file it points to instead of
+        // replacing the link with a regular file. A new file has nothing to
+        // resolve.
+        let path = fs::canonicalize(&path).unwrap_or(path);
+
+        let n_bytes = match fs::metadata(&path) {
+            // Replacing a file that has other hard links would detach it from
+            // them, so it's overwritten in place instead.
+            Ok(ref metadata) if metadata.nlink() > 1 => self.write_in_place(&path)?,
+            metadata => self.write_replacing(&path, metadata.ok())?,
+        };
+
+        self.saved_undo_depth = Some(self.undo_stack.len());
+        Ok(n_bytes)
+    }
+
+    /// Writes all lines to `path`, which has the given `metadata` if it
+    /// exists. So that a failure midway can't leave a truncated file behind,
+    /// the text is first written to a temporary file in the same directory,
+    /// which then replaces the original in a single rename.
+    fn write_replacing(&self, path: &Path, metadata: Option<fs::Metadata>) -> io::Result<usize> {
+        let tmp_path = path.with_file_name(format!(".kilo-{}.tmp", process::id()));
+        // Never follow whatever may already be at the temporary path, e.g. a
+        // symlink planted there.
+        let file = match OpenOptions::new().write(true).create_new(true).open(&tmp_path) {
+            Ok(file) => file,
+            // The file may be writable while its directory isn't, in which
+            // case it can only be overwritten in place.
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return self.write_in_place(path);
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Keep the permissions and the owner of the file being replaced.
+        if let Some(metadata) = metadata {
+            let mode = metadata.permissions().mode();
+            if let Err(e) = file.set_permissions(fs::Permissions::from_mode(mode)) {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+            // Only root may give a file to another user, so if it belongs to
+            // someone else, it's overwritten in place instead.
+            if let Err(_) = fchown(&file, metadata.uid(), metadata.gid()) {
+                let _ = fs::remove_file(&tmp_path);
+                return self.write_in_place(path);
+            }
+        }
+
+        let n_bytes = self.write_synced(file).map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            e
+        })?;
+        if let Err(_) = fs::rename(&tmp_path, path) {
+            // Renaming fails if the two paths are on different devices (e.g.
+            // the target is a mount point itself), so the only option left is
+            // to overwrite the target in place.
+            let result = fs::copy(&tmp_path, path);
+            let _ = fs::remove_file(&tmp_path);
+            result?;
+        }
+        Ok(n_bytes)
+    }
+
+    /// Truncates the file at `path` and writes all lines to it, for when it
+    /// can't be replaced as a whole.
+    fn write_in_place(&self, path: &Path) -> io::Result<usize> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        self.write_synced(file)
+    }
+
+    /// Writes all lines to `file` and makes sure they reach the disk.
+    fn write_synced(&self, file: File) -> io::Result<usize> {
+        let mut writer = io::BufWriter::new(file);
+        let n_bytes = self.write_lines(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(n_bytes)
+    }
+
+    /// Writes all lines to `out`, returning the number of bytes written.
+    fn write_lines<W: Write>(&self, out: &mut W) -> io::Result<usize> {
+        // An empty file stays empty unless some text was added to it.
+        let trailing_newline = self.trailing_newline.unwrap_or_else(|| {
+            self.lines.len() > 1 || !self.lines[0].orig.is_empty()
+        });
+        let mut n_bytes = 0;
+        for (i, line) in self.lines.iter().enumerate() {
+            out.write_all(&line.orig)?;
+            n_bytes += line.orig.len();
+            if i + 1 < self.lines.len() || trailing_newline {
+                out.write_all(b"\n")?;
+                n_bytes += 1;
+            }
+        }
+        Ok(n_bytes)
+    }
+
     pub fn run(&mut self) {
         let mut buf: [u8; 1] = [0; 1];
+        self.update_window_size();
         self.refresh_screen();
-        self.new_status_msg("HELP: Ctrl-C to quit", Duration::from_secs(5));
+        self.show_help();
         loop {
+            if RESIZE_NEEDED.swap(false, Ordering::SeqCst) {
+                self.reflow_display();
+            }
             self.refresh_screen();
+            self.wait_for_key();
+            // The window was resized while waiting, so redraw it before
+            // blocking on the read.
+            if RESIZE_NEEDED.load(Ordering::SeqCst) {
+                continue;
+            }
             // TODO is there a canonical way of getting a single byte from stdin?
-            if let Ok(_) = io::stdin().read_exact(&mut buf) {
+            let result = RawStdin.read(&mut buf);
+            if let Err(ref e) = result {
+                // A signal interrupted the read.
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+            }
+            if let Ok(1) = result {
                 let b = buf[0];
                 if b as char == ctrl_mask('c') {
-                    break;
+                    // The scratch buffer is temporary by design, so there's
+                    // nothing to warn about losing.
+                    if !self.is_dirty() || self.pending_quit || self.path.is_none() {
+                        break;
+                    }
+                    self.pending_quit = true;
+                    self.after_ctrl_u = None;
+                    self.set_message("Unsaved changes. Press Ctrl-C again to quit.");
                 } else {
+                    self.pending_quit = false;
                     self.handle_key(b as char)
                 }
             } else {
@@ -197,354 +987,1482 @@ impl Editor {
         }
     }
 
+    fn show_help(&mut self) {
+        self.set_message("HELP: Ctrl-S to save | Ctrl-C to quit | Ctrl-Z to undo | Alt-Z to redo");
+    }
+
     fn handle_key(&mut self, c: char) {
+        let after_ctrl_u = self.after_ctrl_u.take();
         match c {
             '\x1b' => self.handle_esc_seq_key(),
-            _ => self.handle_input(c)
+            // Depending on the terminal, backspace is sent as either DEL or
+            // Ctrl+H.
+            '\x7f' | '\x08' => self.handle_special_key(Key::Backspace),
+            '\r' => self.handle_special_key(Key::Enter),
+            // Scrolling by half a page is on Alt+D and Alt+U.
+            c if c == ctrl_mask('d') => self.duplicate_line(),
+            c if c == ctrl_mask('u') => self.handle_ctrl_u(),
+            c if c == ctrl_mask('w') => self.delete_word_backward(),
+            // Ctrl+M can't be used since it's the same byte as Enter.
+            c if c == ctrl_mask('l') => self.reflow_display(),
+            c if c == ctrl_mask('g') => self.go_to_line(),
+            c if c == ctrl_mask('f') => self.run_search(SearchDirection::Forward),
+            c if c == ctrl_mask('r') => self.run_search(SearchDirection::Backward),
+            c if c == ctrl_mask('s') => self.save(),
+            c if c == ctrl_mask('z') => self.undo(),
+            c if c == ctrl_mask('k') => self.kill_line(),
+            c if c == ctrl_mask('y') => self.yank(),
+            c if c == ctrl_mask('x') => self.handle_ctrl_x_chord(),
+            // Ctrl+Space is sent as NUL.
+            c if c == ctrl_mask(' ') => match after_ctrl_u {
+                Some(killed) => self.cycle_mark_after_ctrl_u(killed),
+                None => self.push_mark(),
+            },
+            _ => {
+                // Bytes of multi-byte UTF-8 characters arrive one at a time,
+                // so the rest of the character needs to be read in.
+                if let Some(c) = self.read_utf8_char(c as u8) {
+                    self.handle_input(c)
+                }
+            }
+        }
+    }
+
+    /// Reads the key following Ctrl+X and runs the command bound to the
+    /// chord, if any.
+    fn handle_ctrl_x_chord(&mut self) {
+        let mut buf: [u8; 1] = [0; 1];
+        if let Err(_) = RawStdin.read_exact(&mut buf) {
+            return;
+        }
+        match buf[0] as char {
+            c if c == ctrl_mask('x') => self.exchange_point_and_mark(),
+            ';' => self.comment_region(),
+            // Terminals don't send Ctrl+; as a distinct key, so Shift+; is
+            // used instead.
+            ':' => self.uncomment_region(),
+            c if c == ctrl_mask('a') => self.append_to_file(),
+            c if c == ctrl_mask('q') => self.toggle_read_only(),
+            _ => self.new_status_msg("Unknown command", Duration::from_secs(2)),
         }
     }
 
+    /// Deletes from the start of the line to the cursor. Since Ctrl+U followed
+    /// by Ctrl+Space walks back through the mark stack instead, as in emacs,
+    /// whether anything was deleted is remembered for the next key.
+    fn handle_ctrl_u(&mut self) {
+        let undo_depth = self.undo_stack.len();
+        self.kill_to_line_start();
+        self.after_ctrl_u = Some(self.undo_stack.len() > undo_depth);
+    }
+
+    /// Takes back the deletion made by the Ctrl+U before this Ctrl+Space, if
+    /// `killed`, such that it can't be redone or yanked, and walks back
+    /// through the mark stack.
+    fn cycle_mark_after_ctrl_u(&mut self, killed: bool) {
+        if killed {
+            if let Some(edit) = self.undo_stack.pop() {
+                edit.command.unapply(self);
+                let (line, byte) = edit.cursor_before;
+                self.jump_to(line, byte);
+            }
+            self.kill_ring.pop();
+        }
+        self.cycle_mark();
+    }
+
+    /// Reads the remaining bytes of the UTF-8 encoded character starting with
+    /// `first_byte` from stdin, returning None if the encoding is invalid.
+    fn read_utf8_char(&mut self, first_byte: u8) -> Option<char> {
+        let len = match first_byte {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return None,
+        };
+        let mut buf: [u8; 4] = [first_byte, 0, 0, 0];
+        if let Err(_) = RawStdin.read_exact(&mut buf[1..len]) {
+            return None;
+        }
+        std::str::from_utf8(&buf[..len]).ok().and_then(|s| s.chars().next())
+    }
+
     fn handle_esc_seq_key(&mut self) {
         if let Some(key) = self.read_esc_seq_to_key() {
-            match key {
-                Key::ArrowUp => self.cursor_up(),
-                Key::ArrowDown => self.cursor_down(),
-                Key::ArrowLeft => self.cursor_left(),
-                Key::ArrowRight => self.cursor_right(),
-                Key::PageUp => self.page_up(),
-                Key::PageDown => self.page_down(),
-                Key::LineHome => {
-                    while self.cursor.byte > 0 {
-                        self.cursor_left();
-                    }
-                },
-                // FIXME this doesn't work
-                Key::LineEnd => {
-                    while self.cursor.byte + 1 < self.lines[self.cursor.line].len()
-                        && self.cursor.pos.col + 1 < self.window_width {
-                        self.cursor_right();
-                    }
-                },
-                Key::FileHome => {
-                }
-                Key::FileEnd => {
-                }
-                _ => (),
+            self.handle_special_key(key);
+        }
+    }
+
+    fn handle_special_key(&mut self, key: Key) {
+        match key {
+            Key::ArrowUp => self.cursor_up(),
+            Key::ArrowDown => self.cursor_down(),
+            Key::ArrowLeft => self.cursor_left(),
+            Key::ArrowRight => self.cursor_right(),
+            Key::PageUp => self.page_up(),
+            Key::PageDown => self.page_down(),
+            Key::LineHome => self.cursor_to_byte(0),
+            Key::LineEnd => {
+                let line_len = self.lines[self.cursor.line].len();
+                self.cursor_to_byte(line_len);
+            },
+            Key::FileStart => self.cursor_file_start(),
+            Key::FileEnd => self.cursor_file_end(),
+            Key::Delete => self.delete_under_cursor(),
+            Key::Backspace => self.delete_before_cursor(),
+            Key::Enter => self.insert_newline(),
+            Key::WindowTop => self.cursor_to_window_row(0),
+            Key::WindowMiddle => {
+                // If the text ends before the bottom of the window, the
+                // middle is that of the rows that are actually drawn.
+                let (line, row_first_byte) = self.window_row_to_line(self.window_height - 1);
+                let last_row = self.visual_row(line, row_first_byte);
+                self.cursor_to_window_row(last_row / 2);
+            }
+            Key::WindowBottom => {
+                let last_row = self.window_height - 1;
+                self.cursor_to_window_row(last_row);
             }
+            Key::HalfPageDown => self.half_page_down(),
+            Key::HalfPageUp => self.half_page_up(),
+            Key::SaveAs => self.save_as(),
+            Key::Redo => self.redo(),
+            Key::CountWords => self.count_words(),
+            Key::EvalRegion => self.eval_region(),
+            Key::F(1) => self.show_help(),
+            Key::F(_) => (),
+            Key::WordRight => self.cursor_word_right(),
+            Key::WordLeft => self.cursor_word_left(),
+            Key::Escape => (),
+        }
+    }
+
+    /// Lays out the text anew for the current window size and repaints the
+    /// whole screen. This is for when a terminal resize went unnoticed (e.g.
+    /// under tmux), leaving the cursor and rows wrapped for the old size.
+    fn reflow_display(&mut self) {
+        self.update_window_size();
+        // Rows start at multiples of the window width, which may have changed.
+        self.line_offset_byte = self.row_first_byte(self.line_offset, self.line_offset_byte);
+        let (line, byte) = (self.cursor.line, self.cursor.byte);
+        self.set_cursor(line, byte);
+        // The repaint itself happens on the next refresh.
+        self.clear_screen();
+    }
+
+    fn page_down(&mut self) {
+        //let lines_left = self.lines.len() - self.cursor.line;
+        //let at_least_n_rows = cmp::min(self.window_height, lines_left);
+        let mut n_rows_left = self.window_height - 1;
+        while n_rows_left > 0 && self.cursor.line < self.lines.len() {
+            self.cursor_down();
+            n_rows_left -= 1;
+        }
+    }
+
+    fn page_up(&mut self) {
+        let mut n_rows_left = self.window_height - 1;
+        //let n_rows = cmp::min(self.window_height, self.cursor.pos.row);
+        while n_rows_left > 0 && self.cursor.line > 0 {
+            self.cursor_up();
+            n_rows_left -= 1;
+        }
+    }
+
+    /// Scrolls the window down by half its height, like a pager. The cursor
+    /// stays where it is in the text, unless it would scroll out of view, in
+    /// which case it's moved to the top row.
+    fn half_page_down(&mut self) {
+        for _ in 0..self.window_height / 2 {
+            self.scroll_down();
+        }
+        self.ensure_cursor_visible();
+    }
+
+    /// The counterpart of `half_page_down`: scrolls the window up by half its
+    /// height, moving the cursor to the bottom row only if it would otherwise
+    /// scroll out of view.
+    fn half_page_up(&mut self) {
+        for _ in 0..self.window_height / 2 {
+            self.scroll_up();
+        }
+        self.ensure_cursor_visible();
+    }
+
+    /// After the window has been scrolled independently of the cursor, this
+    /// moves the cursor to the nearest row in the window if it's no longer in
+    /// view, and otherwise updates its row to reflect the scrolling.
+    fn ensure_cursor_visible(&mut self) {
+        if self.is_cursor_above_window() {
+            self.cursor_to_window_row(0);
+        } else if self.cursor_visual_row() >= self.window_height {
+            let last_row = self.window_height - 1;
+            self.cursor_to_window_row(last_row);
+        } else {
+            self.cursor.pos.row = self.cursor_visual_row();
+        }
+    }
+
+    /// Moves the cursor down by one row, if possible.
+    fn cursor_down(&mut self) {
+        let row_first_byte = self.cursor_row_first_byte();
+        if let Some((line, row_first_byte)) = self.next_row(self.cursor.line, row_first_byte) {
+            self.cursor_to_row(line, row_first_byte);
+        }
+    }
+
+    /// Moves the cursor up by one row, if possible.
+    fn cursor_up(&mut self) {
+        let row_first_byte = self.cursor_row_first_byte();
+        if let Some((line, row_first_byte)) = self.prev_row(self.cursor.line, row_first_byte) {
+            self.cursor_to_row(line, row_first_byte);
+        }
+    }
+
+    /// Shifts the window down by one row, but does not affect the cursor position.
+    fn scroll_down(&mut self) {
+        if let Some((line, row_first_byte)) = self.next_row(self.line_offset, self.line_offset_byte) {
+            self.line_offset = line;
+            self.line_offset_byte = row_first_byte;
+        }
+    }
+
+    /// Shifts the window up by one row, but does not affect the cursor position.
+    fn scroll_up(&mut self) {
+        if let Some((line, row_first_byte)) = self.prev_row(self.line_offset, self.line_offset_byte) {
+            self.line_offset = line;
+            self.line_offset_byte = row_first_byte;
+        }
+    }
+
+    fn cursor_left(&mut self) {
+        if self.cursor.byte > 0 {
+            let byte = self.char_start(self.cursor.line, self.cursor.byte - 1);
+            self.cursor_to_byte(byte);
+        }
+    }
+
+    fn cursor_right(&mut self) {
+        if self.cursor.byte < self.lines[self.cursor.line].len() {
+            let byte = self.next_char_start(self.cursor.line, self.cursor.byte);
+            self.cursor_to_byte(byte);
+        }
+    }
+
+    /// Returns the offset of the first byte of the UTF-8 character that the
+    /// byte at offset `byte` in the line at index `line` belongs to, so that
+    /// the cursor is never put in the middle of a character.
+    fn char_start(&self, line: usize, byte: usize) -> usize {
+        let render = &self.lines[line].render;
+        let mut byte = byte;
+        while byte > 0 && byte < render.len() && render[byte] & 0xc0 == 0x80 {
+            byte -= 1;
+        }
+        byte
+    }
+
+    /// Returns the offset of the first byte of the UTF-8 character following
+    /// the one at offset `byte` in the line at index `line`, or the line's
+    /// length if it's the last one.
+    fn next_char_start(&self, line: usize, byte: usize) -> usize {
+        let render = &self.lines[line].render;
+        let mut byte = byte + 1;
+        while byte < render.len() && render[byte] & 0xc0 == 0x80 {
+            byte += 1;
+        }
+        byte
+    }
+
+    /// Moves the cursor to the start of the text, which is then shown from its
+    /// first row.
+    fn cursor_file_start(&mut self) {
+        self.jump_to(0, 0);
+    }
+
+    /// Moves the cursor to the end of the last line, scrolling it into view.
+    fn cursor_file_end(&mut self) {
+        let last_line = self.lines.len() - 1;
+        let byte = self.lines[last_line].len();
+        self.jump_to(last_line, byte);
+    }
+
+    /// Moves the cursor past the end of the next word. At the end of a line,
+    /// the cursor moves to the start of the next line instead.
+    fn cursor_word_right(&mut self) {
+        let line = self.cursor.line;
+        let render = &self.lines[line].render;
+        if self.cursor.byte == render.len() {
+            if line + 1 < self.lines.len() {
+                self.jump_to(line + 1, 0);
+            }
+            return;
+        }
+        let mut byte = self.cursor.byte;
+        while byte < render.len() && render[byte] == b' ' {
+            byte += 1;
+        }
+        while byte < render.len() && render[byte] != b' ' {
+            byte += 1;
+        }
+        self.cursor_to_byte(byte);
+    }
+
+    /// Moves the cursor to the start of the previous word. At the start of a
+    /// line, the cursor moves to the end of the previous line instead.
+    fn cursor_word_left(&mut self) {
+        let line = self.cursor.line;
+        if self.cursor.byte == 0 {
+            if line > 0 {
+                let byte = self.lines[line - 1].len();
+                self.jump_to(line - 1, byte);
+            }
+            return;
+        }
+        let render = &self.lines[line].render;
+        let mut byte = self.cursor.byte;
+        while byte > 0 && render[byte - 1] == b' ' {
+            byte -= 1;
+        }
+        while byte > 0 && render[byte - 1] != b' ' {
+            byte -= 1;
+        }
+        self.cursor_to_byte(byte);
+    }
+
+    /// Moves the cursor to the byte at offset `byte` in the line under the
+    /// cursor, as horizontal movement does: the column it ends up in becomes
+    /// the desired column.
+    fn cursor_to_byte(&mut self, byte: usize) {
+        let line = self.cursor.line;
+        self.set_cursor(line, byte);
+        self.cursor.desired_col = self.cursor.pos.col;
+        self.cursor.is_at_eol = byte == self.lines[line].len();
+    }
+
+    /// Moves the cursor to the row of the line at index `line` that starts at
+    /// `row_first_byte`, as vertical movement does: the cursor lands in its
+    /// desired column, or at the end of the row if it's shorter than that.
+    fn cursor_to_row(&mut self, line: usize, row_first_byte: usize) {
+        let last_col = self.row_last_col(&self.lines[line], row_first_byte);
+        let col = {
+            if self.cursor.is_at_eol {
+                last_col
+            } else {
+                cmp::min(last_col, self.cursor.desired_col)
+            }
+        };
+        let line_ref = &self.lines[line];
+        let byte = line_ref.char_byte(line_ref.char_index(row_first_byte) + col);
+        self.set_cursor(line, byte);
+    }
+
+    /// Puts the cursor on the byte at offset `byte` in the line at index
+    /// `line` (which may be one past its last byte), scrolling the window if
+    /// that position is not in view. The desired column is left untouched.
+    fn set_cursor(&mut self, line: usize, byte: usize) {
+        self.cursor.line = line;
+        self.cursor.byte = byte;
+        self.cursor.pos.col = self.lines[line].char_index(byte) % self.window_width;
+        self.scroll_to_cursor();
+    }
+
+    /// Scrolls the window by the least amount necessary for the cursor to be in
+    /// view and updates the cursor's row accordingly.
+    fn scroll_to_cursor(&mut self) {
+        if self.is_cursor_above_window() {
+            self.line_offset = self.cursor.line;
+            self.line_offset_byte = self.cursor_row_first_byte();
+        } else if self.cursor_visual_row() >= self.window_height {
+            // Make the cursor's row the last one in the window by walking
+            // back from it to find the row that needs to be at the top.
+            let mut line = self.cursor.line;
+            let mut row_first_byte = self.cursor_row_first_byte();
+            for _ in 1..self.window_height {
+                match self.prev_row(line, row_first_byte) {
+                    Some(prev) => {
+                        line = prev.0;
+                        row_first_byte = prev.1;
+                    }
+                    None => break,
+                }
+            }
+            self.line_offset = line;
+            self.line_offset_byte = row_first_byte;
+        }
+        self.cursor.pos.row = self.cursor_visual_row();
+    }
+
+    /// Returns the offset of the first byte in the row with the cursor.
+    fn cursor_row_first_byte(&self) -> usize {
+        self.row_first_byte(self.cursor.line, self.cursor.byte)
+    }
+
+    /// Returns the offset of the first byte in the row of the line at index
+    /// `line` that the byte at offset `byte` is drawn on. Each row holds
+    /// `window_width` characters, so rows start at character indices that are
+    /// multiples of it.
+    fn row_first_byte(&self, line: usize, byte: usize) -> usize {
+        let line = &self.lines[line];
+        let char_index = line.char_index(byte);
+        line.char_byte(char_index - char_index % self.window_width)
+    }
+
+    /// Returns the number of rows `line` is wrapped into. Since the cursor
+    /// may be positioned one past the last character of a line (to append to
+    /// it), a line whose number of characters is a multiple of the window
+    /// width has an extra, empty row for that position.
+    fn line_n_rows(&self, line: &Line) -> usize {
+        line.n_chars() / self.window_width + 1
+    }
+
+    /// Returns the last column the cursor may be in on the row of `line` that
+    /// starts at `row_first_byte`. On the line's last row this is the column
+    /// just past its last character.
+    fn row_last_col(&self, line: &Line, row_first_byte: usize) -> usize {
+        cmp::min(line.n_chars() - line.char_index(row_first_byte), self.window_width - 1)
+    }
+
+    /// Returns the line index and first byte of the row after the row of the
+    /// line at index `line` that starts at `row_first_byte`, or None if that
+    /// is the last row of the text.
+    fn next_row(&self, line: usize, row_first_byte: usize) -> Option<(usize, usize)> {
+        let line_ref = &self.lines[line];
+        let next_row_char = line_ref.char_index(row_first_byte) + self.window_width;
+        if next_row_char <= line_ref.n_chars() {
+            Some((line, line_ref.char_byte(next_row_char)))
+        } else if line + 1 < self.lines.len() {
+            Some((line + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// The counterpart of `next_row`: returns the row before the given one,
+    /// or None if it's the first row of the text.
+    fn prev_row(&self, line: usize, row_first_byte: usize) -> Option<(usize, usize)> {
+        let line_ref = &self.lines[line];
+        let row_first_char = line_ref.char_index(row_first_byte);
+        if row_first_char >= self.window_width {
+            Some((line, line_ref.char_byte(row_first_char - self.window_width)))
+        } else if line > 0 {
+            let prev_line = &self.lines[line - 1];
+            let last_row_char = (self.line_n_rows(prev_line) - 1) * self.window_width;
+            Some((line - 1, prev_line.char_byte(last_row_char)))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the row in the window (0 being the top) on which the byte at
+    /// offset `byte` in the line at index `line` is drawn. The position must
+    /// not be above the first row of the window.
+    fn visual_row(&self, line: usize, byte: usize) -> usize {
+        let n_rows_before: usize = self.lines[self.line_offset..line].iter()
+            .map(|line| self.line_n_rows(line))
+            .sum();
+        let row_in_line = self.lines[line].char_index(byte) / self.window_width;
+        let offset_row_in_line =
+            self.lines[self.line_offset].char_index(self.line_offset_byte) / self.window_width;
+        n_rows_before + row_in_line - offset_row_in_line
+    }
+
+    /// Returns whether the row with the cursor is above the first row of the
+    /// window, i.e. it's been scrolled out of view.
+    fn is_cursor_above_window(&self) -> bool {
+        self.cursor.line < self.line_offset
+            || (self.cursor.line == self.line_offset && self.cursor.byte < self.line_offset_byte)
+    }
+
+    /// Returns the row in the window the cursor is on, calculated from the
+    /// text rather than relying on `cursor.pos.row` being up to date.
+    fn cursor_visual_row(&self) -> usize {
+        self.visual_row(self.cursor.line, self.cursor.byte)
+    }
+
+    /// Returns the index of the line and the offset of the first byte in that
+    /// line of the row at index `row` in the window. If the text ends before
+    /// that row, the last row of the text is returned.
+    fn window_row_to_line(&self, row: usize) -> (usize, usize) {
+        let mut line = self.line_offset;
+        let mut row_first_byte = self.line_offset_byte;
+        for _ in 0..row {
+            match self.next_row(line, row_first_byte) {
+                Some(next) => {
+                    line = next.0;
+                    row_first_byte = next.1;
+                }
+                None => break,
+            }
+        }
+        (line, row_first_byte)
+    }
+
+    /// Moves the cursor to the row at index `row` in the window (or to the
+    /// last row of the text, if it ends before that), without scrolling. Like
+    /// vertical movement, the cursor tries to stay in its desired column.
+    fn cursor_to_window_row(&mut self, row: usize) {
+        let (line, row_first_byte) = self.window_row_to_line(row);
+        self.cursor_to_row(line, row_first_byte);
+    }
+
+    /// Asks the user for a line number and moves the cursor to the start of
+    /// that line, scrolling it into the middle of the window.
+    fn go_to_line(&mut self) {
+        let answer = match self.prompt("Go to line: ") {
+            Some(answer) => answer,
+            None => return,
+        };
+        match answer.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= self.lines.len() => {
+                self.jump_to(n - 1, 0);
+                self.center_cursor();
+                self.pulse_cursor_line();
+            }
+            Ok(_) => {
+                let msg = format!("Line number must be between 1 and {}", self.lines.len());
+                self.new_status_msg(&msg, Duration::from_secs(2));
+            }
+            Err(_) => {
+                let msg = format!("Not a line number: {}", answer);
+                self.new_status_msg(&msg, Duration::from_secs(2));
+            }
+        }
+    }
+
+    /// Searches for text typed in the message bar in `direction`, moving the
+    /// cursor to the nearest match as the query grows. Ctrl+F and Ctrl+R move
+    /// on to the next and the previous match. Enter, or any other special key,
+    /// leaves the cursor at the match, while Escape puts it back where it was.
+    ///
+    /// The search starts out with the query of the last one and goes to its
+    /// next match right away; typing replaces that query.
+    fn run_search(&mut self, direction: SearchDirection) {
+        let orig_cursor = self.cursor;
+        let orig_line_offset = (self.line_offset, self.line_offset_byte);
+        let origin = {
+            let line = &self.lines[self.cursor.line];
+            (self.cursor.line, self.render_to_orig_byte(line, self.cursor.byte))
+        };
+        self.search_direction = direction;
+        let mut query = self.search_query.clone();
+        let mut is_last_query = !query.is_empty();
+        // The (line, original byte) position the search continues from: the
+        // current match, or the cursor's original position.
+        let mut anchor = origin;
+        // Whether to search at all after a key press, and if so, whether to
+        // skip a match at the anchor, i.e. move on to the next one.
+        let mut search = is_last_query;
+        let mut skip_anchor = true;
+        let mut is_cancelled = false;
+        // A key that ended the search and is to be handled as usual.
+        let mut next_key = None;
+        let mut buf: [u8; 1] = [0; 1];
+        loop {
+            if search {
+                self.search_match = None;
+                let found = if query.is_empty() {
+                    None
+                } else {
+                    self.find_match(query.as_bytes(), anchor, skip_anchor)
+                };
+                if let Some((line, start)) = found {
+                    anchor = (line, start);
+                    let end = start + query.len();
+                    let (start, end) = {
+                        let line = &self.lines[line];
+                        (self.orig_to_render_byte(line, start), self.orig_to_render_byte(line, end))
+                    };
+                    self.jump_to(line, start);
+                    self.pulse_cursor_line();
+                    self.search_match = Some((line, start, end));
+                }
+            }
+
+            let prompt = match (self.search_direction, query.is_empty() || self.search_match.is_some()) {
+                (SearchDirection::Forward, true) => "Search: ",
+                (SearchDirection::Forward, false) => "Search (no match): ",
+                (SearchDirection::Backward, true) => "Search backward: ",
+                (SearchDirection::Backward, false) => "Search backward (no match): ",
+            };
+            self.refresh_screen();
+            self.draw_prompt(prompt, &query);
+
+            if let Err(_) = RawStdin.read_exact(&mut buf) {
+                break;
+            }
+            search = true;
+            skip_anchor = false;
+            match buf[0] as char {
+                '\x1b' => match self.read_esc_seq_to_key() {
+                    Some(Key::Escape) => {
+                        is_cancelled = true;
+                        break;
+                    }
+                    // Any other key leaves the cursor at the match, from
+                    // which it then does what it always does (e.g. an arrow
+                    // key moves on from there).
+                    Some(key) => {
+                        next_key = Some(key);
+                        break;
+                    }
+                    None => search = false,
+                },
+                c if c == ctrl_mask('c') => {
+                    is_cancelled = true;
+                    break;
+                }
+                '\r' => break,
+                c if c == ctrl_mask('f') || c == ctrl_mask('r') => {
+                    self.search_direction = if c == ctrl_mask('f') {
+                        SearchDirection::Forward
+                    } else {
+                        SearchDirection::Backward
+                    };
+                    if query.is_empty() {
+                        query = self.search_query.clone();
+                    }
+                    skip_anchor = true;
+                }
+                '\x7f' | '\x08' => {
+                    query.pop();
+                    is_last_query = false;
+                }
+                c if c.is_control() => search = false,
+                _ => match self.read_utf8_char(buf[0]) {
+                    Some(c) => {
+                        if is_last_query {
+                            // A new query is being typed, which is searched
+                            // for from where the search started.
+                            query.clear();
+                            anchor = origin;
+                            is_last_query = false;
+                        }
+                        query.push(c);
+                    }
+                    None => search = false,
+                },
+            }
+        }
+        if is_cancelled {
+            self.cursor = orig_cursor;
+            self.line_offset = orig_line_offset.0;
+            self.line_offset_byte = orig_line_offset.1;
+        }
+        if !query.is_empty() {
+            self.search_query = query;
+        }
+        self.search_match = None;
+
+        if let Some(key) = next_key {
+            self.handle_special_key(key);
+        }
+    }
+
+    /// Returns the position of the nearest occurrence of `query` from
+    /// `anchor` in the search direction, as a (line, original byte) pair. An
+    /// occurrence at `anchor` itself is only returned if `skip_anchor` is not
+    /// set.
+    fn find_match(&self, query: &[u8], anchor: (usize, usize), skip_anchor: bool)
+            -> Option<(usize, usize)> {
+        let after_anchor = (anchor.0, anchor.1 + 1);
+        match (self.search_direction, skip_anchor) {
+            (SearchDirection::Forward, false) => self.find_next_match(query, anchor),
+            (SearchDirection::Forward, true) => self.find_next_match(query, after_anchor),
+            (SearchDirection::Backward, false) => self.find_prev_match(query, after_anchor),
+            (SearchDirection::Backward, true) => self.find_prev_match(query, anchor),
+        }
+    }
+
+    /// Returns the line and the offset in its original bytes of the first
+    /// occurrence of `query` at or after `from`, a (line, original byte) pair,
+    /// wrapping around to the start of the text.
+    fn find_next_match(&self, query: &[u8], from: (usize, usize)) -> Option<(usize, usize)> {
+        let n_lines = self.lines.len();
+        // The line of `from` is visited twice: first for the part after
+        // `from`, then, after wrapping around, for the part before it.
+        for i in 0..n_lines + 1 {
+            let line = (from.0 + i) % n_lines;
+            let orig = &self.lines[line].orig;
+            let start = if i == 0 { cmp::min(from.1, orig.len()) } else { 0 };
+            if let Some(j) = find_bytes(&orig[start..], query) {
+                return Some((line, start + j));
+            }
+        }
+        None
+    }
+
+    /// Returns the line and the offset in its original bytes of the last
+    /// occurrence of `query` before `from`, a (line, original byte) pair,
+    /// wrapping around to the end of the text.
+    fn find_prev_match(&self, query: &[u8], from: (usize, usize)) -> Option<(usize, usize)> {
+        let n_lines = self.lines.len();
+        for i in 0..n_lines + 1 {
+            let line = (from.0 + n_lines - i) % n_lines;
+            let orig = &self.lines[line].orig;
+            // Matches must start before `from`, but may extend beyond it.
+            let end = if i == 0 { cmp::min(from.1 + query.len() - 1, orig.len()) } else { orig.len() };
+            if let Some(j) = rfind_bytes(&orig[..end], query) {
+                return Some((line, j));
+            }
+        }
+        None
+    }
+
+    /// Highlights the line under the cursor for a moment, to be used after
+    /// the cursor jumped far.
+    fn pulse_cursor_line(&mut self) {
+        self.pulse_line = Some((self.cursor.line, Instant::now()));
+    }
+
+    /// Returns whether the line at index `line` is to be highlighted as the
+    /// cursor just jumped to it.
+    fn is_line_pulsing(&self, line: usize) -> bool {
+        match self.pulse_line {
+            Some((pulse_line, start)) => pulse_line == line && start.elapsed() < pulse_duration(),
+            None => false,
+        }
+    }
+
+    /// Waits until a key is pressed. If the pulse of a line or the status
+    /// message runs out in the meantime, the screen is redrawn without it, so
+    /// that neither lingers until the next key press.
+    fn wait_for_key(&mut self) {
+        loop {
+            let pulse_end = self.pulse_line.map(|(_, start)| start + pulse_duration());
+            let msg_end = if self.status_msg.data.is_empty() {
+                None
+            } else {
+                Some(self.status_msg.timestamp + self.status_msg.timeout)
+            };
+            let end = match (pulse_end, msg_end) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (Some(end), None) | (None, Some(end)) => Some(end),
+                (None, None) => None,
+            };
+
+            let timeout_ms = match end {
+                Some(end) => {
+                    let now = Instant::now();
+                    let timeout = if end > now { end - now } else { Duration::new(0, 0) };
+                    // Round up so as not to wake up just before the end.
+                    let timeout_ms = timeout.as_secs() * 1000
+                        + (timeout.subsec_nanos() as u64 + 999_999) / 1_000_000;
+                    timeout_ms as i32
+                }
+                // Nothing to wait for but a key press.
+                None => -1,
+            };
+            let mut fds = [PollFd::new(io::stdin().as_raw_fd(), EventFlags::POLLIN)];
+            match poll::poll(&mut fds, timeout_ms) {
+                Ok(0) => (),
+                // Either a key was pressed or a signal (e.g. SIGWINCH)
+                // arrived, which the caller handles.
+                _ => return,
+            }
+
+            if pulse_end.map_or(false, |end| end <= Instant::now()) {
+                self.pulse_line = None;
+            }
+            self.refresh_screen();
+        }
+    }
+
+    /// Scrolls the window so that the cursor's row is in its middle, or as
+    /// close to it as the start of the text allows.
+    fn center_cursor(&mut self) {
+        let mut top = (self.cursor.line, self.cursor_row_first_byte());
+        for _ in 0..self.window_height / 2 {
+            match self.prev_row(top.0, top.1) {
+                Some(prev) => top = prev,
+                None => break,
+            }
+        }
+        self.line_offset = top.0;
+        self.line_offset_byte = top.1;
+        self.cursor.pos.row = self.cursor_visual_row();
+    }
+
+    /// Pushes the cursor's position onto the mark stack, dropping the oldest
+    /// mark if the stack is full.
+    fn push_mark(&mut self) {
+        if self.mark_stack.len() == MARK_STACK_SIZE {
+            self.mark_stack.remove(0);
+        }
+        self.mark_stack.push((self.cursor.line, self.cursor.byte));
+        self.new_status_msg("Mark set", Duration::from_secs(2));
+    }
+
+    /// Moves the cursor to the most recent mark, which is replaced by the
+    /// position the cursor was moved from, so that repeating this returns to
+    /// where the cursor was.
+    fn exchange_point_and_mark(&mut self) {
+        if let Some((line, byte)) = self.mark_stack.pop() {
+            self.mark_stack.push((self.cursor.line, self.cursor.byte));
+            self.jump_to(line, byte);
+            self.pulse_cursor_line();
+        } else {
+            self.new_status_msg("No mark set", Duration::from_secs(2));
+        }
+    }
+
+    /// Moves the cursor to the most recent mark and moves that mark to the
+    /// bottom of the stack, so that repeating this walks back through all
+    /// marks.
+    fn cycle_mark(&mut self) {
+        if let Some((line, byte)) = self.mark_stack.pop() {
+            self.mark_stack.insert(0, (line, byte));
+            self.jump_to(line, byte);
+            self.pulse_cursor_line();
+        } else {
+            self.new_status_msg("No mark set", Duration::from_secs(2));
+        }
+    }
+
+    /// Shows the number of lines, words and characters between the most
+    /// recent mark and the cursor, or in the whole text if no mark is set.
+    fn count_words(&mut self) {
+        let (what, (start, end)) = match self.region() {
+            Some(region) => ("Region", region),
+            None => {
+                let last_line = self.lines.len() - 1;
+                ("Buffer", ((0, 0), (last_line, self.lines[last_line].len())))
+            }
+        };
+
+        let text = self.text_between(start, end);
+        let text = String::from_utf8_lossy(&text);
+        let n_chars = text.chars().count();
+        let n_words = text.split_whitespace().count();
+        let n_lines = end.0 - start.0 + 1;
+
+        let msg = format!("{} has {} line{}, {} word{}, and {} character{}", what,
+                          n_lines, if n_lines == 1 { "" } else { "s" },
+                          n_words, if n_words == 1 { "" } else { "s" },
+                          n_chars, if n_chars == 1 { "" } else { "s" });
+        self.set_message(&msg);
+    }
+
+    /// Returns the start and end of the region, as (line, byte) pairs like the
+    /// cursor's position: the text between the most recent mark and the
+    /// cursor. Returns None if no mark is set.
+    fn region(&self) -> Option<((usize, usize), (usize, usize))> {
+        let &(mark_line, mark_byte) = self.mark_stack.last()?;
+        // The mark may be out of date if the text was edited since.
+        let mark_line = cmp::min(mark_line, self.lines.len() - 1);
+        let mark = (mark_line, cmp::min(mark_byte, self.lines[mark_line].len()));
+        let cursor = (self.cursor.line, self.cursor.byte);
+        Some((cmp::min(mark, cursor), cmp::max(mark, cursor)))
+    }
+
+    /// Returns the original bytes of the text from `start` up to `end`, both
+    /// (line, byte) pairs like the cursor's position, with the lines separated
+    /// by new-line characters.
+    fn text_between(&self, start: (usize, usize), end: (usize, usize)) -> Vec<u8> {
+        let mut text = vec![];
+        for line in start.0..end.0 + 1 {
+            let from = if line == start.0 {
+                self.render_to_orig_byte(&self.lines[line], start.1)
+            } else {
+                0
+            };
+            let to = if line == end.0 {
+                self.render_to_orig_byte(&self.lines[line], end.1)
+            } else {
+                self.lines[line].orig.len()
+            };
+            text.extend_from_slice(&self.lines[line].orig[from..to]);
+            if line < end.0 {
+                text.push(b'\n');
+            }
+        }
+        text
+    }
+
+    /// Evaluates the Rust expression in the region, or in the line under the
+    /// cursor if no mark is set, and shows the result in the message bar. The
+    /// expression is compiled into a throwaway program with `rustc`, which
+    /// prints it with `{:?}`.
+    fn eval_region(&mut self) {
+        let is_rust = self.path.as_ref()
+            .and_then(|path| path.extension())
+            .map_or(false, |ext| ext == "rs");
+        if !is_rust {
+            self.new_status_msg("Only Rust expressions can be evaluated", Duration::from_secs(2));
+            return;
+        }
+        let expr = match self.region() {
+            Some((start, end)) => self.text_between(start, end),
+            None => self.lines[self.cursor.line].orig.clone(),
+        };
+        let expr = String::from_utf8_lossy(&expr).into_owned();
+
+        self.set_message("Evaluating...");
+        self.refresh_screen();
+        let msg = match eval_rust_expr(&expr) {
+            // Fit the result into the single row of the message bar.
+            Ok(result) => format!("=> {}", result.trim_end().replace('\n', " ")),
+            Err(e) => e,
+        };
+        self.new_status_msg(&msg, Duration::from_secs(10));
+    }
+
+    /// Moves the cursor to `byte` in the line at index `line`. Since the text
+    /// may have been edited since the position was recorded, it's clamped to
+    /// the end of the text.
+    fn jump_to(&mut self, line: usize, byte: usize) {
+        let line = cmp::min(line, self.lines.len() - 1);
+        let byte = cmp::min(byte, self.lines[line].len());
+        self.cursor.line = line;
+        self.cursor_to_byte(byte);
+    }
+
+    /// This function is called after encountering a \x1b escape character from
+    /// stdin. It reads in the rest of the escape sequence and translates it to
+    /// an optional Key value, or None, if no valid (or implemented) sequence
+    /// was deteced. Alt+<key> combinations are sent as \x1b followed by the
+    /// key, so they are handled here too.
+    fn read_esc_seq_to_key(&mut self) -> Option<Key> {
+        // The terminal sends escape sequences all at once, so if nothing
+        // follows right away, the Escape key was pressed on its own. Blocking
+        // here would otherwise take the next key as part of the sequence.
+        if !is_input_pending(esc_seq_timeout()) {
+            return Some(Key::Escape);
+        }
+
+        let mut buf: [u8; 3] = [0; 3];
+        if let Err(_) = RawStdin.read_exact(&mut buf[..1]) {
+            return None;
+        }
+
+        let c = buf[0] as char;
+        if c == '[' || c == 'O' {
+            if let Err(_) = RawStdin.read_exact(&mut buf[1..2]) {
+                return None;
+            }
+        }
+
+        if c == '[' {
+            let c = buf[1] as char;
+            if c >= '0' && c <= '9' {
+                // The number may have more than one digit (e.g. \x1b[15~ for
+                // F5), so read on until the character ending it.
+                let mut n = buf[1] - b'0';
+                let c = loop {
+                    if let Err(_) = RawStdin.read_exact(&mut buf[2..3]) {
+                        return None;
+                    }
+                    let c = buf[2] as char;
+                    match c.to_digit(10) {
+                        Some(digit) => n = n.saturating_mul(10).saturating_add(digit as u8),
+                        None => break c,
+                    }
+                };
+
+                return if c == '~' {
+                    match n {
+                        1 | 7 => Some(Key::LineHome),
+                        4 | 8 => Some(Key::LineEnd),
+                        3 => Some(Key::Delete),
+                        5 => Some(Key::PageUp),
+                        6 => Some(Key::PageDown),
+                        // The numbers of the function keys skip 16 and 22.
+                        11..=15 => Some(Key::F(n - 10)),
+                        17..=21 => Some(Key::F(n - 11)),
+                        23 | 24 => Some(Key::F(n - 12)),
+                        _ =>  None
+                    }
+                } else if c == ';' {
+                    // Keys with modifiers are sent as \x1b[1;<modifier><key>,
+                    // where the modifier 5 stands for Ctrl.
+                    let mut buf: [u8; 2] = [0; 2];
+                    if let Err(_) = RawStdin.read_exact(&mut buf) {
+                        return None;
+                    }
+                    match (buf[0] as char, buf[1] as char) {
+                        ('5', 'C') => Some(Key::WordRight),
+                        ('5', 'D') => Some(Key::WordLeft),
+                        ('5', 'H') => Some(Key::FileStart),
+                        ('5', 'F') => Some(Key::FileEnd),
+                        _ => None
+                    }
+                } else { None };
+            } else {
+                let c = buf[1] as char;
+                match c {
+                    'A' => Some(Key::ArrowUp),
+                    'B' => Some(Key::ArrowDown),
+                    'C' => Some(Key::ArrowRight),
+                    'D' => Some(Key::ArrowLeft),
+                    'H' => Some(Key::LineHome),
+                    // The Linux console sends F1 to F5 as \x1b[[A to \x1b[[E.
+                    '[' => {
+                        if let Err(_) = RawStdin.read_exact(&mut buf[2..3]) {
+                            return None;
+                        }
+                        match buf[2] {
+                            b'A'..=b'E' => Some(Key::F(buf[2] - b'A' + 1)),
+                            _ => None
+                        }
+                    }
+                    _ => None
+                }
+            }
+        } else if c == 'O' {
+            let c = buf[1] as char;
+            match c {
+                'H' => Some(Key::LineHome),
+                'F' => Some(Key::LineEnd),
+                'P' => Some(Key::F(1)),
+                'Q' => Some(Key::F(2)),
+                'R' => Some(Key::F(3)),
+                'S' => Some(Key::F(4)),
+                _ => None
+            }
+        } else {
+            match c {
+                'h' => Some(Key::WindowTop),
+                'm' => Some(Key::WindowMiddle),
+                'l' => Some(Key::WindowBottom),
+                'd' => Some(Key::HalfPageDown),
+                'u' => Some(Key::HalfPageUp),
+                // Terminals send Ctrl+Shift+S the same as Ctrl+S.
+                's' => Some(Key::SaveAs),
+                'z' => Some(Key::Redo),
+                'f' => Some(Key::WordRight),
+                'b' => Some(Key::WordLeft),
+                // Ctrl+Alt+W
+                '\x17' => Some(Key::CountWords),
+                // Ctrl+Alt+E
+                '\x05' => Some(Key::EvalRegion),
+                _ => None
+            }
+        }
+    }
+
+    fn handle_input(&mut self, c: char) {
+        // Ignore control characters that aren't bound to anything.
+        if c.is_control() && c != '\t' {
+            return;
         }
+        let mut buf: [u8; 4] = [0; 4];
+        let len = c.encode_utf8(&mut buf).len();
+        self.insert_bytes(&buf[..len]);
     }
 
-    fn page_down(&mut self) {
-        //let lines_left = self.lines.len() - self.cursor.line;
-        //let at_least_n_rows = cmp::min(self.window_height, lines_left);
-        let mut n_rows_left = self.window_height - 1;
-        while n_rows_left > 0 && self.cursor.line < self.lines.len() {
-            self.cursor_down();
-            n_rows_left -= 1;
+    /// Returns whether the text may not be modified, telling the user so if
+    /// that's the case.
+    fn check_read_only(&mut self) -> bool {
+        if self.read_only {
+            self.new_status_msg("Buffer is read-only", Duration::from_secs(2));
         }
+        self.read_only
     }
 
-    fn page_up(&mut self) {
-        let mut n_rows_left = self.window_height - 1;
-        //let n_rows = cmp::min(self.window_height, self.cursor.pos.row);
-        while n_rows_left > 0 && self.cursor.line > 0 {
-            self.cursor_up();
-            n_rows_left -= 1;
-        }
+    /// Returns whether the text has been modified since it was last loaded or
+    /// saved.
+    fn is_dirty(&self) -> bool {
+        self.saved_undo_depth != Some(self.undo_stack.len())
     }
 
-    /// Moves the cursor down by one row, if possible.
-    fn cursor_down(&mut self) {
-        // Check if cursor is at the bottom of the window.
-        if self.cursor.pos.row + 1 == self.window_height {
-            self.scroll_down();
+    /// Applies `command` to the text and records it so that it can be undone.
+    /// The cursor is moved to `cursor_after`, given as the index of a line and
+    /// an offset into its original bytes.
+    fn execute<C: Command + 'static>(&mut self, command: C, cursor_after: (usize, usize)) {
+        let cursor_before = (self.cursor.line, self.cursor.byte);
+        command.apply(self);
+        // The saved text is among the undone modifications, which are about
+        // to be dropped, so it can't be returned to.
+        if self.saved_undo_depth.map_or(false, |depth| depth > self.undo_stack.len()) {
+            self.saved_undo_depth = None;
         }
 
-        // Note that this is indexed from the beginning of the line, whereas
-        // curr_last_pos_row_offset is indexed from the beginning of the row.
-        let next_rows_len = self.curr_line_next_rows_len();
+        let (line, orig_byte) = cursor_after;
+        let byte = self.orig_to_render_byte(&self.lines[line], orig_byte);
+        self.jump_to(line, byte);
+
+        self.undo_stack.push(Edit {
+            command: Box::new(command),
+            cursor_before: cursor_before,
+            cursor_after: (line, byte),
+        });
+        // The undone modifications may no longer apply to the new text.
+        self.redo_stack.clear();
+    }
 
-        log(format!("DOWN: cursor: {:?}, row_last_byte: {}, next_rows_len: {}, line_offset: {}, line_offset_byte: {}, line.len: {}",
-                    self.cursor, self.curr_last_pos_line_offset(), next_rows_len, self.line_offset,
-                    self.line_offset_byte, self.lines[self.cursor.line].len()).as_bytes());
+    /// Reverts the most recent modification that hasn't been undone yet.
+    fn undo(&mut self) {
+        if self.check_read_only() {
+            return;
+        }
+        if let Some(edit) = self.undo_stack.pop() {
+            edit.command.unapply(self);
+            let (line, byte) = edit.cursor_before;
+            self.jump_to(line, byte);
+            self.redo_stack.push(edit);
+        } else {
+            self.new_status_msg("Nothing to undo", Duration::from_secs(2));
+        }
+    }
 
-        if next_rows_len > 0 {
-            // We're not at the end of the line, which is merely wrapped, so
-            // just go down one row staying on the same line.
-            if self.cursor.pos.row + 1 < self.window_height {
-                self.cursor.pos.row += 1;
-            }
+    /// Applies the most recently undone modification again.
+    fn redo(&mut self) {
+        if self.check_read_only() {
+            return;
+        }
+        if let Some(edit) = self.redo_stack.pop() {
+            edit.command.apply(self);
+            let (line, byte) = edit.cursor_after;
+            self.jump_to(line, byte);
+            self.undo_stack.push(edit);
+        } else {
+            self.new_status_msg("Nothing to redo", Duration::from_secs(2));
+        }
+    }
 
-            let next_row_len = cmp::min(next_rows_len, self.window_width);
-            let col = {
-                if self.cursor.is_at_eol {
-                    next_row_len - 1
-                } else {
-                    cmp::min(self.cursor.pos.col, next_row_len - 1)
-                }
-            };
+    /// Inserts `bytes` into the line under the cursor, in front of the byte
+    /// under the cursor, and moves the cursor past them.
+    fn insert_bytes(&mut self, bytes: &[u8]) {
+        if self.check_read_only() {
+            return;
+        }
+        if self.lines.is_empty() {
+            self.lines.push(Line { orig: vec![], render: vec![] });
+        }
 
-            log(format!("DOWN|wrap: next_row_len: {}, col: {}", next_row_len, col).as_bytes());
-
-            self.cursor.pos.col = col;
-            self.cursor.byte = self.curr_last_pos_line_offset() + 1 + col;
-        } else if self.cursor.line + 1 < self.lines.len() {
-            // Go down one row to the next line if cursor is not already on the
-            // last line.
-            self.cursor.line += 1;
-            if self.cursor.pos.row + 1 < self.window_height {
-                self.cursor.pos.row += 1;
-            }
-
-            // Next line might be shorter than current cursor column position.
-            let col = {
-                let line = &self.lines[self.cursor.line];
-                if line.is_empty() {
-                    0
-                } else if self.cursor.is_at_eol {
-                    cmp::min(line.len(), self.window_width) - 1
-                } else {
-                    cmp::min(line.len() - 1, self.cursor.pos.col)
-                }
-            };
+        let line = self.cursor.line;
+        let at = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+        let command = InsertChar { line: line, at: at, bytes: bytes.to_vec() };
+        self.execute(command, (line, at + bytes.len()));
+    }
 
-            log(format!("DOWN|new-line: col: {}", col).as_bytes());
+    /// Splits the line under the cursor in two at the cursor and moves the
+    /// cursor to the start of the new, second line.
+    fn insert_newline(&mut self) {
+        if self.check_read_only() {
+            return;
+        }
+        let line = self.cursor.line;
+        let at = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+        self.execute(SplitLine { line: line, at: at }, (line + 1, 0));
+    }
 
-            self.cursor.pos.col = col;
-            self.cursor.byte = col;
+    /// Inserts a copy of the line under the cursor after it and moves the
+    /// cursor down to the same position in the copy.
+    fn duplicate_line(&mut self) {
+        if self.check_read_only() {
+            return;
         }
+        let line = self.cursor.line;
+        let at = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+        self.execute(DuplicateLine { line: line }, (line + 1, at));
     }
 
-    /// Shifts the window down by one row, but does not affect the cursor position.
-    fn scroll_down(&mut self) {
-        // Only scroll down if there's at least one line left, or if we're on
-        // the last line but it's wrapped, so we can scroll to its next row.
-        if self.cursor.line + 1 < self.lines.len() || self.curr_line_next_rows_len() > 0 {
-            // The top row may be part of a wrapped line, so need to check if we
-            // need to advance to the next line or just adjust the byte offset
-            // from which to show the line.
-            if self.line_offset_byte + self.window_width < self.lines[self.line_offset].len() {
-                self.line_offset_byte += self.window_width;
-                self.cursor.pos.row -= 1;
-                log(format!("DOWN|scroll|wrap: line_offset: {}, line_offset_byte: {}, curr_line_next_rows_len: {}",
-                            self.line_offset, self.line_offset_byte, self.curr_line_next_rows_len()).as_bytes());
-            } else {
-                self.line_offset += 1;
-                self.line_offset_byte = 0;
-                log(format!("DOWN|scroll|new-line: line_offset: {}, line_offset_byte: {}, self.cursor.line: {}",
-                            self.line_offset, self.line_offset_byte, self.cursor.line).as_bytes());
-                self.cursor.pos.row -= 1;
-            }
+    /// Deletes the line under the cursor, putting it into the kill ring, and
+    /// moves the cursor to the start of the line that takes its place.
+    fn kill_line(&mut self) {
+        if self.check_read_only() {
+            return;
         }
+        let line = self.cursor.line;
+        let orig = self.lines[line].orig.clone();
+        let is_only_line = self.lines.len() == 1;
+        // If the last line is deleted, the cursor moves up to the new last.
+        let cursor_line = if line + 1 < self.lines.len() || is_only_line { line } else { line - 1 };
+
+        let mut killed = orig.clone();
+        killed.push(b'\n');
+        self.push_kill(killed);
+        let command = DeletedLine { line: line, orig: orig, is_only_line: is_only_line };
+        self.execute(command, (cursor_line, 0));
     }
 
-    /// Moves the cursor up by one row, if possible.
-    fn cursor_up(&mut self) {
-        // Cursor may have reached the top of the window.
-        if self.cursor.pos.row == 0 {
-            self.scroll_up();
+    /// Deletes the part of the line under the cursor before the cursor,
+    /// putting it into the kill ring.
+    fn kill_to_line_start(&mut self) {
+        if self.cursor.byte == 0 || self.check_read_only() {
+            return;
+        }
+        let line = self.cursor.line;
+        let mut end = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+        // If the cursor is inside an expanded tab, the tab is before the
+        // cursor.
+        if self.orig_to_render_byte(&self.lines[line], end) < self.cursor.byte {
+            end += 1;
         }
 
-        if self.cursor.byte >= self.window_width {
-            // Line is wrapped so we don't have to skip to the previous line,
-            // only the row.
-            if self.cursor.pos.row > 0 {
-                self.cursor.pos.row -= 1;
-            }
+        let bytes = self.lines[line].orig[..end].to_vec();
+        self.push_kill(bytes.clone());
+        self.execute(DeleteRange { line: line, at: 0, bytes: bytes }, (line, 0));
+    }
 
-            if self.cursor.is_at_eol {
-                // Get the total length of the previous rows and subtract one to get the last
-                // byte's offset in line of the previous row's last byte.
-                self.cursor.byte = (self.cursor.byte / self.window_width) * self.window_width - 1;
-                self.cursor.pos.col = self.cursor.byte % self.window_width;
-            } else {
-                self.cursor.byte -= self.window_width;
+    /// Deletes the word before the cursor, along with any whitespace between
+    /// it and the cursor, putting it into the kill ring.
+    fn delete_word_backward(&mut self) {
+        if self.cursor.byte == 0 || self.check_read_only() {
+            return;
+        }
+        let line = self.cursor.line;
+        let mut end = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+        // If the cursor is inside an expanded tab, the tab is before the
+        // cursor.
+        if self.orig_to_render_byte(&self.lines[line], end) < self.cursor.byte {
+            end += 1;
+        }
+        let start = {
+            let orig = &self.lines[line].orig;
+            let is_whitespace = |b: u8| b == b' ' || b == b'\t';
+            let mut start = end;
+            while start > 0 && is_whitespace(orig[start - 1]) {
+                start -= 1;
             }
-        } else if self.cursor.line > 0 {
-            // Cursor is on the first row of this line, so go to the previous
-            // line.
-            self.cursor.line -= 1;
-            if self.cursor.pos.row > 0 {
-                self.cursor.pos.row -= 1;
+            while start > 0 && !is_whitespace(orig[start - 1]) {
+                start -= 1;
             }
+            start
+        };
 
-            // Previous line might be shorter than current cursor column
-            // position, in which case the cursor needs to be moved to its end,
-            // and it might be wrapping, in which case the cursor needs to be
-            // positioned on the last wrap of the line.
-            let line = &self.lines[self.cursor.line];
-            if line.is_empty() {
-                self.cursor.pos.col = 0;
-                self.cursor.byte = 0;
-            } else {
-                if line.len() <= self.window_width {
-                    let col = {
-                        if self.cursor.is_at_eol {
-                            line.len() - 1
-                        } else {
-                            cmp::min(line.len() - 1, self.cursor.pos.col)
-                        }
-                    };
+        let bytes = self.lines[line].orig[start..end].to_vec();
+        self.push_kill(bytes.clone());
+        self.execute(DeleteRange { line: line, at: start, bytes: bytes }, (line, start));
+    }
 
-                    self.cursor.pos.col = col;
-                    self.cursor.byte = col;
-                } else {
-                    // Use integer truncation to first get the number of full
-                    // rows this line is broken up into.
-                    let last_row_first_byte = (line.len() / self.window_width) * self.window_width;
-                    let col = {
-                        let last_row_len = line.len() - last_row_first_byte;
-                        if self.cursor.is_at_eol {
-                            last_row_len - 1
-                        } else {
-                            cmp::min(last_row_len - 1, self.cursor.pos.col)
-                        }
-                    };
+    /// Comments out every non-empty line between the line with the most
+    /// recent mark and the line under the cursor (or just the latter if no mark
+    /// is set) by prepending the line comment prefix of the file's language.
+    fn comment_region(&mut self) {
+        self.edit_region_lines(|orig, prefix| {
+            if orig.is_empty() {
+                return None;
+            }
+            let mut commented = prefix.as_bytes().to_vec();
+            commented.push(b' ');
+            commented.extend_from_slice(orig);
+            Some(commented)
+        });
+    }
 
-                    self.cursor.byte = last_row_first_byte + col;
-                    self.cursor.pos.col = col;
-                }
+    /// Removes the line comment prefix, and a space following it, from every
+    /// line in the same lines `comment_region` works on. Lines that are not
+    /// comments are left as they are.
+    fn uncomment_region(&mut self) {
+        self.edit_region_lines(|orig, prefix| {
+            let indent = orig.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+            if !orig[indent..].starts_with(prefix.as_bytes()) {
+                return None;
             }
-        }
+            let mut end = indent + prefix.len();
+            if orig.get(end) == Some(&b' ') {
+                end += 1;
+            }
+            let mut uncommented = orig[..indent].to_vec();
+            uncommented.extend_from_slice(&orig[end..]);
+            Some(uncommented)
+        });
     }
 
-    /// Shifts the window up by one row, but does not affect the cursor position.
-    fn scroll_up(&mut self) {
-        // The top row may be part of a wrapped line, so need to check if we
-        // need to advance to the previous line or just adjust the byte offset
-        // from which to show the line.
-        if self.line_offset_byte >= self.window_width {
-            self.line_offset_byte -= self.window_width;
-            //self.cursor.pos.row += 1;
-        } else if self.line_offset > 0 {
-            self.line_offset -= 1;
-            //self.cursor.pos.row += 1;
-            // If the previous line is wrapped, it must not be drawn from its first byte.
-            let line = &self.lines[self.line_offset];
-            if line.len() > self.window_width {
-                self.line_offset_byte = (line.len() / self.window_width) * self.window_width;
-            } else {
-                self.line_offset_byte = 0;
+    /// Replaces each line in the region with what `edit` returns for it,
+    /// given the line and the file's line comment prefix, or leaves the line
+    /// as it is if None is returned. The edit can be undone as a whole.
+    fn edit_region_lines<F>(&mut self, edit: F)
+        where F: Fn(&[u8], &str) -> Option<Vec<u8>>
+    {
+        if self.check_read_only() {
+            return;
+        }
+        let prefix = match self.path.as_ref().and_then(|path| line_comment_prefix(path)) {
+            Some(prefix) => prefix,
+            None => {
+                self.new_status_msg("No comment syntax is known for this file", Duration::from_secs(2));
+                return;
+            }
+        };
+        let cursor_line = self.cursor.line;
+        let (first, last) = match self.mark_stack.last() {
+            Some(&(mark_line, _)) => {
+                // The mark may be out of date if the text was edited since.
+                let mark_line = cmp::min(mark_line, self.lines.len() - 1);
+                (cmp::min(mark_line, cursor_line), cmp::max(mark_line, cursor_line))
             }
+            None => (cursor_line, cursor_line),
+        };
+
+        let old: Vec<Vec<u8>> = self.lines[first..last + 1].iter()
+            .map(|line| line.orig.clone())
+            .collect();
+        let new: Vec<Vec<u8>> = old.iter()
+            .map(|orig| edit(orig, prefix).unwrap_or_else(|| orig.clone()))
+            .collect();
+        if new == old {
+            return;
         }
+
+        // Keep the cursor on the same character, or at the start of the line
+        // if that was removed.
+        let at = self.render_to_orig_byte(&self.lines[cursor_line], self.cursor.byte);
+        let (old_len, new_len) = (old[cursor_line - first].len(), new[cursor_line - first].len());
+        let at = if new_len >= old_len {
+            at + (new_len - old_len)
+        } else {
+            at.saturating_sub(old_len - new_len)
+        };
+        self.execute(ReplaceLines { first: first, old: old, new: new }, (cursor_line, at));
     }
 
-    fn cursor_left(&mut self) {
-        if self.cursor.pos.col > 0 {
-            if self.cursor.pos.col == self.curr_last_pos_row_offset() {
-                self.cursor.is_at_eol = false;
+    /// Inserts the most recently killed text at the cursor and moves the
+    /// cursor past it.
+    fn yank(&mut self) {
+        if self.check_read_only() {
+            return;
+        }
+        let text = match self.kill_ring.last() {
+            Some(text) => text.clone(),
+            None => {
+                self.new_status_msg("Kill ring is empty", Duration::from_secs(2));
+                return;
             }
-            self.cursor.pos.col -= 1;
-            self.cursor.byte -= 1;
+        };
+        let line = self.cursor.line;
+        let at = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+        let command = InsertText { line: line, at: at, text: text };
+        let cursor_after = command.end();
+        self.execute(command, cursor_after);
+    }
+
+    /// Adds `text` to the kill ring, dropping the oldest kill if it's full.
+    fn push_kill(&mut self, text: Vec<u8>) {
+        if self.kill_ring.len() == KILL_RING_SIZE {
+            self.kill_ring.remove(0);
         }
+        self.kill_ring.push(text);
     }
 
-    fn cursor_right(&mut self) {
-        if self.cursor.byte + 1 < self.lines[self.cursor.line].len()
-            && self.cursor.pos.col + 1 < self.window_width {
-            self.cursor.pos.col += 1;
-            self.cursor.byte += 1;
-            if self.cursor.pos.col == self.curr_last_pos_row_offset() {
-                self.cursor.is_at_eol = true;
+    /// Deletes the character before the cursor. At the start of a line, the
+    /// line is joined with the previous one instead.
+    fn delete_before_cursor(&mut self) {
+        if self.check_read_only() {
+            return;
+        }
+        let line = self.cursor.line;
+        if self.cursor.byte > 0 {
+            let mut end = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+            // If the cursor is inside an expanded tab, the tab is the
+            // character before the cursor.
+            if self.orig_to_render_byte(&self.lines[line], end) < self.cursor.byte {
+                end += 1;
             }
+            let start = {
+                // Step back over the continuation bytes of a multi-byte
+                // UTF-8 character to delete it as a whole.
+                let orig = &self.lines[line].orig;
+                let mut start = end - 1;
+                while start > 0 && orig[start] & 0xc0 == 0x80 {
+                    start -= 1;
+                }
+                start
+            };
+
+            let bytes = self.lines[line].orig[start..end].to_vec();
+            self.execute(DeleteRange { line: line, at: start, bytes: bytes }, (line, start));
+        } else if line > 0 {
+            let prev_len = self.lines[line - 1].orig.len();
+            self.execute(MergeLines { line: line - 1, at: prev_len }, (line - 1, prev_len));
         }
     }
 
-    /// Returns the position of the last byte in the row under the cursor.
-    fn curr_last_pos_row_offset(&self) -> usize {
-        if self.lines.is_empty() {
-            return 0;
+    /// Deletes the character under the cursor. At the end of a line, the next
+    /// line is joined onto it instead.
+    fn delete_under_cursor(&mut self) {
+        if self.check_read_only() {
+            return;
         }
-        let line = &self.lines[self.cursor.line];
-        if line.is_empty() {
-            0
-        } else {
-            assert!(self.window_width > 0);
-            cmp::min(line.len(), self.window_width) - 1
+        let line = self.cursor.line;
+        if self.cursor.byte < self.lines[line].len() {
+            let start = self.render_to_orig_byte(&self.lines[line], self.cursor.byte);
+            let end = {
+                // Delete multi-byte UTF-8 characters as a whole.
+                let orig = &self.lines[line].orig;
+                let mut end = start + 1;
+                while end < orig.len() && orig[end] & 0xc0 == 0x80 {
+                    end += 1;
+                }
+                end
+            };
+
+            let bytes = self.lines[line].orig[start..end].to_vec();
+            self.execute(DeleteRange { line: line, at: start, bytes: bytes }, (line, start));
+        } else if line + 1 < self.lines.len() {
+            // The cursor stays put but it's no longer at the end of the line.
+            let len = self.lines[line].orig.len();
+            self.execute(MergeLines { line: line, at: len }, (line, len));
         }
     }
 
-    /// Similary to curr_last_pos_row_offset, but returns the that position's absolute
-    /// offset from the line's start.
-    fn curr_last_pos_line_offset(&self) -> usize {
-        self.cursor.byte + self.curr_last_pos_row_offset() - self.cursor.pos.col
+    /// Splits the line at index `line` in two at offset `at` into its original
+    /// bytes, the second part becoming the next line.
+    fn split_line(&mut self, line: usize, at: usize) {
+        let rest = self.lines[line].orig.split_off(at);
+        let render = self.line_orig_to_render(&rest);
+        self.update_render(line);
+        self.lines.insert(line + 1, Line { orig: rest, render: render });
     }
 
-    /// Returns the total number of bytes of all rows in this line after the row
-    /// under the cursor.
-    fn curr_line_next_rows_len(&self) -> usize {
-        let line_len = self.lines[self.cursor.line].len();
-        let row_last_byte = self.curr_last_pos_line_offset();
-        if row_last_byte + 1 >= line_len { 0 } else { line_len - row_last_byte - 1 }
+    /// Appends the line after the line at index `line` to it, removing the
+    /// former from the text.
+    fn join_next_line(&mut self, line: usize) {
+        let next = self.lines.remove(line + 1);
+        self.lines[line].orig.extend(next.orig);
+        self.update_render(line);
     }
 
-    /// This function is called after encountering a \x1b escape character from
-    /// stdin. It reads in the rest of the escape sequence and translates it to
-    /// an optional Key value, or None, if no valid (or implemented) sequence
-    /// was deteced.
-    fn read_esc_seq_to_key(&mut self) -> Option<Key> {
-        let mut buf: [u8; 3] = [0; 3];
-        if let Err(_) = io::stdin().read_exact(&mut buf[..2]) {
-            return None;
-        }
-
-        let c = buf[0] as char;
-        if c == '[' {
-            let c = buf[1] as char;
-            if c >= '0' && c <= '9' {
-                if let Err(_) = io::stdin().read_exact(&mut buf[2..3]) {
-                    return None;
-                }
+    /// Recomputes how the line at index `line` is rendered after its original
+    /// bytes have been changed.
+    fn update_render(&mut self, line: usize) {
+        let render = self.line_orig_to_render(&self.lines[line].orig);
+        self.lines[line].render = render;
+    }
 
-                let c = buf[2] as char;
-                return if c == '~' {
-                    let c = buf[1] as char;
-                    match c {
-                        '1' | '7' => Some(Key::LineHome),
-                        '4' | '8' => Some(Key::LineEnd),
-                        '3' => Some(Key::Delete),
-                        '5' => Some(Key::PageUp),
-                        '6' => Some(Key::PageDown),
-                        _ =>  None
-                    }
-                } else { None };
-            } else {
-                let c = buf[1] as char;
-                match c {
-                    'A' => Some(Key::ArrowUp),
-                    'B' => Some(Key::ArrowDown),
-                    'C' => Some(Key::ArrowRight),
-                    'D' => Some(Key::ArrowLeft),
-                    'H' => Some(Key::LineHome),
-                    _ => None
-                }
-            }
-        } else if c == 'O' {
-            let c = buf[1] as char;
-            match c {
-                'H' => Some(Key::LineHome),
-                'F' => Some(Key::LineEnd),
-                _ => None
+    /// Returns the offset in `line`'s original bytes of the byte that is
+    /// rendered at offset `render_byte`. Every offset within an expanded tab
+    /// maps to the tab itself.
+    fn render_to_orig_byte(&self, line: &Line, render_byte: usize) -> usize {
+        let mut render_len = 0;
+        for (i, b) in line.orig.iter().enumerate() {
+            render_len += self.rendered_len(*b, render_len);
+            if render_len > render_byte {
+                return i;
             }
-        } else {
-            None
         }
+        line.orig.len()
     }
 
-    fn handle_input(&mut self, _c: char) {
+    /// The inverse of `render_to_orig_byte`: returns the offset in `line`'s
+    /// rendered bytes at which the original byte at `orig_byte` is rendered.
+    fn orig_to_render_byte(&self, line: &Line, orig_byte: usize) -> usize {
+        line.orig[..orig_byte].iter()
+            .fold(0, |render_len, b| render_len + self.rendered_len(*b, render_len))
+    }
+
+    /// Returns the number of bytes `b` is rendered as if it follows
+    /// `render_len` rendered bytes. This is only ever not 1 for tabs, which
+    /// are expanded to the next tab stop.
+    fn rendered_len(&self, b: u8, render_len: usize) -> usize {
+        if b as char == '\t' {
+            let tab_width = self.config.tab_width as usize;
+            tab_width - render_len % tab_width
+        } else {
+            1
+        }
     }
 
     fn refresh_screen(&mut self) {
-        // Query window size as it may have been changed since the last redraw.
-        // TODO if possible, listen to window resize events.
-        self.update_window_size();
         // Hide cursor while redrawing to avoid glitching.
         self.hide_cursor();
         self.move_cursor(Pos { row: 0, col: 0 });
         // Append text to write buffer while clearing old data.
         self.build_rows();
         self.build_status_bar();
-        self.update_status_msg();
+        self.build_message_bar();
         // (Rust giving me crap for directly passing self.cursor.pos.)
         let cursor = self.cursor.pos;
         // Move cursor back to its original position.
@@ -554,16 +2472,67 @@ impl Editor {
         self.flush_write_buf();
     }
 
+    /// Shows `prompt` in the message bar and reads the user's answer,
+    /// echoing it as it's typed. Returns None if the user cancels with Escape
+    /// or Ctrl+C, or enters nothing. The message bar's previous content is
+    /// shown again on the next refresh.
+    fn prompt(&mut self, prompt: &str) -> Option<String> {
+        let mut answer = String::new();
+        let mut buf: [u8; 1] = [0; 1];
+        loop {
+            self.refresh_screen();
+            self.draw_prompt(prompt, &answer);
+
+            if let Err(_) = RawStdin.read_exact(&mut buf) {
+                return None;
+            }
+            match buf[0] as char {
+                '\x1b' => match self.read_esc_seq_to_key() {
+                    Some(Key::Escape) => return None,
+                    // Other special keys, e.g. arrow keys, have no use in
+                    // the prompt.
+                    _ => (),
+                },
+                c if c == ctrl_mask('c') => return None,
+                '\r' => return if answer.is_empty() { None } else { Some(answer) },
+                '\x7f' | '\x08' => {
+                    answer.pop();
+                }
+                c if c.is_control() => (),
+                _ => {
+                    if let Some(c) = self.read_utf8_char(buf[0]) {
+                        answer.push(c);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Shows `prompt` followed by the user's `answer` so far in the message
+    /// bar, with the cursor after the answer.
+    fn draw_prompt(&mut self, prompt: &str, answer: &str) {
+        // Only show as much of the end of the answer, where the cursor is,
+        // as fits into the message bar.
+        let msg = format!("{}{}", prompt, answer);
+        let n_chars = msg.chars().count();
+        let n_skipped_chars = (n_chars + 1).saturating_sub(self.window_width);
+        let msg: String = msg.chars().skip(n_skipped_chars).collect();
+        let msg_row = self.window_height + 1;
+        self.move_cursor(Pos { row: msg_row, col: 0 });
+        self.clear_row();
+        self.write_buf.extend(msg.as_bytes().iter());
+        self.move_cursor(Pos { row: msg_row, col: n_chars - n_skipped_chars });
+        self.flush_write_buf();
+    }
+
     fn line_orig_to_render(&self, line: &[u8]) -> Vec<u8> {
         let mut render = vec![];
-        for (pos, b) in line.iter().enumerate() {
+        for b in line.iter() {
             if *b as char == '\t' {
-                let mut i = pos + 1;
-                render.push(' ' as u8);
-                while i % self.config.tab_width as usize != 0 {
-                    render.push(' ' as u8);
-                    i += 1;
-                }
+                // Tab stops are relative to the rendered line, not the
+                // original, which may have had other tabs before this one.
+                let n_spaces = self.rendered_len(*b, render.len());
+                render.extend(std::iter::repeat(' ' as u8).take(n_spaces));
             } else {
                 render.push(*b);
             }
@@ -572,68 +2541,74 @@ impl Editor {
     }
 
     fn build_rows(&mut self) {
-        let mut n_rows_drawn = 0;
-        for line in self.lines.iter().skip(self.line_offset) {
-            if n_rows_drawn == self.window_height {
-                break;
+        // The line and the offset of the first byte in it of the next row to
+        // draw, or None if all of the text has been drawn.
+        let mut next_row = Some((self.line_offset, self.line_offset_byte));
+        for _ in 0..self.window_height {
+            let is_pulsing = match next_row {
+                Some((line, _)) => self.is_line_pulsing(line),
+                None => false,
+            };
+            if is_pulsing {
+                // Clearing the row fills it with the current background color,
+                // so the whole row is highlighted.
+                self.defer_esc_seq("103m");
             }
-
-            // The line might be longer than the width of our window, so it needs
-            // to be split accross rows and wrapped. Count how many bytes are left in
-            // the row to draw.
-            let (mut n_bytes_left, mut offset) = {
-                if n_rows_drawn == 0 {
-                    // This is the first line to draw which may not be drawn
-                    // from its first byte if window begins after a wrap.
-                    (line.len() - self.line_offset_byte, self.line_offset_byte)
-                } else {
-                    (line.len(), 0)
+            self.clear_row();
+
+            if let Some((line, offset)) = next_row {
+                // The line might be longer than the width of our window, so it
+                // is split accross rows and wrapped.
+                let line_ref = &self.lines[line];
+                let end = line_ref.char_byte(line_ref.char_index(offset) + self.window_width);
+                let row = &line_ref.render[offset..end];
+                // The escape sequences to insert into the row, each before the
+                // byte at the given offset in the row.
+                let mut esc_seqs = vec![];
+                // The limit is in characters, so it's turned into the offset of
+                // the first byte past it.
+                let max_len = self.config.max_line_length.map(|max_len| line_ref.char_byte(max_len));
+                match max_len {
+                    Some(max_len) if end > max_len => {
+                        // Give the columns beyond the limit a red background.
+                        let n_ok_bytes = if offset < max_len { max_len - offset } else { 0 };
+                        esc_seqs.push((n_ok_bytes, "\x1b[41m"));
+                        // Go back to the row's own background, which is not
+                        // the default one if the line is pulsing.
+                        esc_seqs.push((row.len(), if is_pulsing { "\x1b[103m" } else { "\x1b[49m" }));
+                    }
+                    _ => (),
                 }
-            };
+                match self.search_match {
+                    Some((match_line, start, match_end))
+                        if match_line == line && start < end && match_end > offset => {
+                        // Invert the colors of the part of the match in this
+                        // row.
+                        esc_seqs.push((cmp::max(start, offset) - offset, "\x1b[7m"));
+                        esc_seqs.push((cmp::min(match_end, end) - offset, "\x1b[27m"));
+                    }
+                    _ => (),
+                }
+                esc_seqs.sort_by_key(|&(at, _)| at);
 
-            // It's an empty line.
-            if n_bytes_left == 0 {
-                // Clear row.
-                self.write_buf.extend("\x1b[K".as_bytes());
-                n_rows_drawn += 1;
-                if n_rows_drawn < self.window_height {
-                    self.write_buf.extend("\r\n".as_bytes());
-                } else {
-                    self.write_buf.extend(" ".as_bytes());
+                let mut written = 0;
+                for (at, esc_seq) in esc_seqs {
+                    self.write_buf.extend(&row[written..at]);
+                    self.write_buf.extend(esc_seq.as_bytes());
+                    written = at;
                 }
+                self.write_buf.extend(&row[written..]);
+                next_row = self.next_row(line, offset);
             } else {
-                // Split up line into rows.
-                while n_bytes_left > 0 && n_rows_drawn < self.window_height {
-                    let end = offset + cmp::min(self.window_width, n_bytes_left);
-                    let row = &line.render[offset..end];
-
-                    assert!(row.len() > 0);
-                    //log(format!("bytes left: {}, offset: {}, row.len: {}",
-                            //n_bytes_left, offset, row.len()).as_bytes());
-
-                    // Clear row.
-                    // TODO we should use self.clear_row but can't due to ownership
-                    self.write_buf.extend("\x1b[K".as_bytes());
-                    self.write_buf.extend(row);
-                    self.write_buf.extend("\r\n".as_bytes());
-
-                    offset += row.len();
-                    n_bytes_left -= row.len();
-                    n_rows_drawn += 1;
-                }
+                // There may not be enough text to fill all the rows of the
+                // window, so fill the rest with '~'s.
+                self.write_buf.push('~' as u8);
             }
-        }
 
-        log(format!("window height: {}, rows drawn: {}",
-                    self.window_height, n_rows_drawn).as_bytes());
-        // There may not be enough text to fill all the rows of the window, so
-        // fill the rest with '~'s.
-        let n_empty_rows = self.window_height - n_rows_drawn;
-        if n_empty_rows > 0 {
-            for _ in 1..(n_empty_rows) {
-                self.write_buf.extend("~\r\n".as_bytes());
-                self.clear_row();
+            if is_pulsing {
+                self.defer_esc_seq("49m");
             }
+            self.write_buf.extend("\r\n".as_bytes());
         }
     }
 
@@ -641,79 +2616,89 @@ impl Editor {
         // TODO also count escape sequences
         self.write_buf.reserve(self.window_width);
 
-        // Invert colors.
-        self.defer_esc_seq("1m");
         // Make text bold.
+        self.defer_esc_seq("1m");
+        // Invert colors.
         self.defer_esc_seq("7m");
 
         let sep = " | ";
-        let line_count = {
-            let mut buf = self.lines.len().to_string();
-            if self.lines.len() == 1 {
-                buf += " line";
-            } else {
-                buf += " lines";
+        // Both the line and the column are shown 1-based, as is customary.
+        // The column counts characters, not bytes.
+        let col = self.lines[self.cursor.line].char_index(self.cursor.byte);
+        let cursor_pos = format!("{}/{}, col {}", self.cursor.line + 1, self.lines.len(), col + 1);
+        let file_type = file_type(self.path.as_ref().map(|path| path.as_path()));
+        let file_status = {
+            let mut buf = self.file_name();
+            if self.is_dirty() {
+                buf += " [modified]";
+            }
+            if self.read_only {
+                buf += " [read-only]";
             }
             buf
         };
-        let cursor_pos = {
-            let mut buf = self.cursor.line.to_string();
-            buf += ":";
-            buf += &self.cursor.pos.col.to_string()[..];
-            buf
-        };
-        let (n_used_bytes, n_path_bytes) = {
-            // NOTE: count separators as well: one separator between path and
-            // cursor position, and one between the latter and line count.
-            let mut n_used_bytes = cursor_pos.len() + line_count.len() + sep.len() * 1;
-            let n_path_bytes = cmp::min(self.window_width - n_used_bytes, self.path.len());
-            n_used_bytes += n_path_bytes;
-            (n_used_bytes, n_path_bytes)
+        let (n_used_bytes, n_file_status_bytes) = {
+            // NOTE: count separators as well: one separator between the cursor
+            // position and the file type.
+            let mut n_used_bytes = cmp::min(cursor_pos.len() + sep.len() + file_type.len(),
+                                            self.window_width);
+            let n_file_status_bytes = cmp::min(self.window_width - n_used_bytes, file_status.len());
+            n_used_bytes += n_file_status_bytes;
+            (n_used_bytes, n_file_status_bytes)
         };
 
-        self.write_buf.extend(self.path.as_bytes().iter().take(n_path_bytes));
+        self.write_buf.extend(file_status.as_bytes().iter().take(n_file_status_bytes));
         // Fill up empty space.
-        //self.write_buf.extend(std::iter::repeat(' ' as u8).take(self.window_width - n_used_bytes));
         for _ in 0..self.window_width - n_used_bytes {
             self.write_buf.push(' ' as u8);
         }
-        self.write_buf.extend(cursor_pos.as_bytes().iter());
-        self.write_buf.extend(sep.as_bytes().iter());
-        self.write_buf.extend(line_count.as_bytes().iter());
+        let right = format!("{}{}{}", cursor_pos, sep, file_type);
+        let n_right_bytes = n_used_bytes - n_file_status_bytes;
+        self.write_buf.extend(right.as_bytes().iter().take(n_right_bytes));
 
         log(format!("status bar buffer: {:?}", &self.write_buf[self.write_buf.len() - self.window_width..]).as_bytes());
         // Revert invert colors.
         self.defer_esc_seq("m");
     }
 
+    /// Shows `msg` in the message bar for the default amount of time.
+    fn set_message(&mut self, msg: &str) {
+        self.new_status_msg(msg, Duration::from_secs(5));
+    }
+
+    /// Shows `msg` in the message bar for `timeout`.
     fn new_status_msg(&mut self, msg: &str, timeout: Duration) {
-        //let len = cmp::min(self.window_width, msg.len());
-        //self.write_buf.extend(msg.as_bytes().iter().take(len));
         self.status_msg = StatusMsg {
             data: msg.to_string(),
             timestamp: Instant::now(),
             timeout: timeout,
         };
-        self.write_status_msg();
     }
 
-    fn update_status_msg(&mut self) {
-        let now = Instant::now();
-        if now.duration_since(self.status_msg.timestamp) <= self.status_msg.timeout {
-            self.write_status_msg();
+    /// Draws the status message in the message bar below the status bar, or
+    /// leaves it empty if the message has been shown for long enough.
+    fn build_message_bar(&mut self) {
+        // Clear the message bar so that no part of a longer, previous message
+        // is left over.
+        let msg_row = self.window_height + 1;
+        self.move_cursor(Pos { row: msg_row, col: 0 });
+        self.clear_row();
+
+        if self.status_msg.timestamp.elapsed() < self.status_msg.timeout {
+            let len = cmp::min(self.window_width, self.status_msg.data.len());
+            self.write_buf.extend(self.status_msg.data.as_bytes().iter().take(len));
         } else {
             self.status_msg.data.clear();
         }
     }
 
-    fn write_status_msg(&mut self) {
-        let len = cmp::min(self.window_width, self.status_msg.data.len());
-        self.write_buf.extend(self.status_msg.data.as_bytes().iter().take(len));
-    }
-
     fn flush_write_buf(&mut self) {
-        io::stdout().write(&self.write_buf).unwrap();
-        io::stdout().flush().unwrap();
+        let mut stdout = io::stdout();
+        // There is no one to tell if the terminal can't be written to, but the
+        // editor should still exit cleanly rather than panic in raw mode.
+        if let Err(e) = stdout.write_all(&self.write_buf).and_then(|_| stdout.flush()) {
+            log(format!("failed to write to the terminal: {}", e).as_bytes());
+        }
         // Does not alter its capacity.
         self.write_buf.clear();
     }
@@ -750,6 +2735,20 @@ impl Editor {
     }
 
     fn update_window_size(&mut self) {
+        match query_window_size(io::stdout().as_raw_fd()) {
+            // Some terminals (e.g. serial consoles) report a zero size.
+            Ok((cols, rows)) if cols > 0 && rows > 2 => {
+                self.window_width = cols;
+                // NOTE: subtract 2 from the result: 1 for the status bar and 1
+                // for the status message bar.
+                self.window_height = rows - 2;
+                return;
+            }
+            _ => (),
+        }
+
+        // If the terminal size can't be queried directly, fall back to
+        // finding the bottom right corner with the cursor.
         // Move cursor as far right and down as we can (set_cursor_pos not used
         // on purpose as it uses a different escape sequence which does not
         // ensure that it won't move the cursor beyond the confines of the
@@ -773,7 +2772,7 @@ impl Editor {
         // So if we generously assume each number to be 3 digits long, 10
         // bytes should be enough to allocate only once.
         let mut response = String::with_capacity(10);
-        for r in io::stdin().bytes() {
+        for r in RawStdin.bytes() {
             match r {
                 Ok(c) => {
                     if c == 'R' as u8 {
@@ -814,6 +2813,248 @@ impl Drop for Editor {
     }
 }
 
+/// Returns the prefix that starts a line comment in the language of the file
+/// at `path`, judging by its extension, or None if it's not known.
+fn line_comment_prefix(path: &Path) -> Option<&'static str> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("rs") | Some("c") | Some("h") | Some("cpp") | Some("go") | Some("js") => Some("//"),
+        Some("py") | Some("sh") | Some("rb") | Some("toml") => Some("#"),
+        _ => None,
+    }
+}
+
+/// Returns the name of the type of the file at `path`, judging by its
+/// extension, or `text` if it's not known or there is no file.
+fn file_type(path: Option<&Path>) -> &'static str {
+    match path.and_then(|path| path.extension()).and_then(|ext| ext.to_str()) {
+        Some("rs") => "rust",
+        Some("c") | Some("h") => "c",
+        Some("cpp") => "cpp",
+        Some("go") => "go",
+        Some("js") => "javascript",
+        Some("py") => "python",
+        Some("sh") => "sh",
+        Some("rb") => "ruby",
+        Some("toml") => "toml",
+        Some("md") => "markdown",
+        _ => "text",
+    }
+}
+
+/// Returns the name of the template for files like the one at `path`: its
+/// extension, or `git-commit` for git commit messages.
+fn template_name(path: &Path) -> Option<&str> {
+    if path.file_name().map_or(false, |name| name == "COMMIT_EDITMSG") {
+        Some("git-commit")
+    } else {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("md") => Some("md"),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the template for the file at `path` from
+/// `~/.config/kilo-rust/templates`, with its placeholders expanded, or
+/// returns None if there is no such template.
+fn read_template(path: &Path) -> Option<Vec<u8>> {
+    let name = template_name(path)?;
+    let home = env::var("HOME").ok()?;
+    let template_path = Path::new(&home).join(".config/kilo-rust/templates").join(name);
+    let mut template = String::new();
+    File::open(template_path).ok()?.read_to_string(&mut template).ok()?;
+
+    let file_name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    let author = env::var("GIT_AUTHOR_NAME").or_else(|_| env::var("USER")).unwrap_or_default();
+    let template = template
+        .replace("%DATE%", &today())
+        .replace("%AUTHOR%", &author)
+        .replace("%FILENAME%", &file_name);
+    Some(template.into_bytes())
+}
+
+/// Returns today's date (in UTC) in the form `YYYY-MM-DD`.
+fn today() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // Convert days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Compiles and runs a program printing the Rust expression `expr`, returning
+/// its output, or the first line of the compiler's errors or a description of
+/// what else went wrong.
+fn eval_rust_expr(expr: &str) -> Result<String, String> {
+    // The temporary directory is shared with other users, who must not be
+    // able to plant or swap the files, so they go into a directory of our
+    // own that is created anew, failing if something is already there.
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    let dir = env::temp_dir().join(format!("kilo-eval-{}-{}", process::id(), nanos));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    let src_path = dir.join("eval.rs");
+    let bin_path = dir.join("eval");
+    let src = format!("fn main() {{ println!(\"{{:?}}\", {{ {} }}); }}\n", expr);
+
+    let result = (|| {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&src_path)
+            .and_then(|mut file| file.write_all(src.as_bytes()))
+            .map_err(|e| format!("Can't write {}: {}", src_path.display(), e))?;
+
+        let mut rustc = process::Command::new("rustc");
+        rustc.arg("-o").arg(&bin_path).arg(&src_path);
+        let output = run_with_timeout(rustc, eval_timeout())
+            .map_err(|e| format!("Can't run rustc: {}", e))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let error = stderr.lines()
+                .find(|line| line.starts_with("error"))
+                .unwrap_or("rustc failed");
+            return Err(error.to_string());
+        }
+
+        let output = run_with_timeout(process::Command::new(&bin_path), eval_timeout())
+            .map_err(|e| format!("Can't run the expression: {}", e))?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(stderr.lines().next().unwrap_or("The expression failed").to_string())
+        }
+    })();
+
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Runs `command` with its output captured, killing it if it doesn't finish
+/// within `timeout`.
+fn run_with_timeout(mut command: process::Command, timeout: Duration) -> io::Result<process::Output> {
+    let mut child = command
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    let start = Instant::now();
+    while child.try_wait()?.is_none() {
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    child.wait_with_output()
+}
+
+/// How long evaluating an expression, both compiling and running it, may
+/// take at most per step.
+fn eval_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Returns the offset of the first occurrence of `needle` in `haystack`. An
+/// empty `needle` is never found.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Returns the offset of the last occurrence of `needle` in `haystack`. An
+/// empty `needle` is never found.
+fn rfind_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+    haystack.windows(needle.len()).rposition(|window| window == needle)
+}
+
+/// Stdin without the buffering of `io::stdin()`, which reads ahead any input
+/// that is available, hiding it from `poll`.
+struct RawStdin;
+
+impl Read for RawStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe {
+            libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+}
+
+/// Changes the owner and the group of `file`.
+fn fchown(file: &File, uid: u32, gid: u32) -> io::Result<()> {
+    if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns whether there is input to read on stdin within `timeout`.
+fn is_input_pending(timeout: Duration) -> bool {
+    let timeout_ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000;
+    let mut fds = [PollFd::new(io::stdin().as_raw_fd(), EventFlags::POLLIN)];
+    match poll::poll(&mut fds, timeout_ms as i32) {
+        Ok(n) => n > 0,
+        Err(_) => false,
+    }
+}
+
+/// How long to wait for the rest of an escape sequence after \x1b before
+/// taking it to be the Escape key.
+fn esc_seq_timeout() -> Duration {
+    Duration::from_millis(100)
+}
+
+/// How long a line is highlighted after the cursor jumped to it.
+fn pulse_duration() -> Duration {
+    Duration::from_millis(200)
+}
+
+/// Returns the number of columns and rows of the terminal `fd` refers to.
+fn query_window_size(fd: RawFd) -> nix::Result<(usize, usize)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    Errno::result(unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) })?;
+    Ok((size.ws_col as usize, size.ws_row as usize))
+}
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    RESIZE_NEEDED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGWINCH, sent when the terminal is resized, set `RESIZE_NEEDED`.
+/// SA_RESTART is set, so that system calls interrupted by the signal (e.g.
+/// writing the screen) don't fail halfway through. The screen is still
+/// redrawn right away, as `poll` in `wait_for_key` is never restarted.
+fn install_sigwinch_handler() {
+    let action = SigAction::new(SigHandler::Handler(handle_sigwinch), SaFlags::SA_RESTART,
+                                SigSet::empty());
+    unsafe {
+        signal::sigaction(Signal::SIGWINCH, &action).unwrap();
+    }
+}
+
 fn init_log() {
     OpenOptions::new()
         .write(true)
@@ -849,14 +3090,35 @@ fn main() {
         &raw_termios,
     ).unwrap();
 
-    let config = Config { tab_width: 4 };
+    install_sigwinch_handler();
+
+    // Projects differ in how long lines may be, so overly long lines are only
+    // highlighted if the limit is set, either here or in the file itself.
+    let max_line_length = env::var("KILO_MAX_LINE_LENGTH").ok()
+        .and_then(|value| value.parse().ok());
+    let max_line_length_action = env::var("KILO_MAX_LINE_LENGTH_ACTION").ok()
+        .and_then(|value| MaxLineLengthAction::from_name(&value))
+        .unwrap_or(MaxLineLengthAction::Warn);
+    let config = Config {
+        tab_width: 4,
+        max_line_length: max_line_length,
+        max_line_length_action: max_line_length_action,
+        auto_backup: true,
+    };
 
     let args: Vec<String> = args().collect();
-    if args.len() > 1 {
-        Editor::open_file(config, Path::new(&args[1])).unwrap().run();
+    let open_error = if args.len() > 1 {
+        match Editor::open_file(config, Path::new(&args[1])) {
+            Ok(mut editor) => {
+                editor.run();
+                None
+            }
+            Err(e) => Some(e),
+        }
     } else {
-        // TODO report error or ask for a file name
-    }
+        Editor::new_scratch(config).run();
+        None
+    };
 
     // Restore the original termios config.
     termios::tcsetattr(
@@ -864,4 +3126,66 @@ fn main() {
         termios::SetArg::TCSANOW,
         &orig_termios,
     ).unwrap();
+
+    // Only report the error once the terminal is back to normal.
+    if let Some(e) = open_error {
+        eprintln!("Can't open {}: {}", args[1], e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an editor with the given lines in a window large enough for
+    /// none of them to wrap.
+    fn editor_with_lines(lines: &[&str]) -> Editor {
+        let config = Config {
+            tab_width: 4,
+            max_line_length: None,
+            max_line_length_action: MaxLineLengthAction::Warn,
+            auto_backup: false,
+        };
+        let mut editor = Editor::new(config, None);
+        editor.window_width = 80;
+        editor.window_height = 24;
+        for line in lines {
+            let render = editor.line_orig_to_render(line.as_bytes());
+            editor.lines.push(Line { orig: line.as_bytes().to_vec(), render: render });
+        }
+        editor
+    }
+
+    #[test]
+    fn delete_only_char_of_line() {
+        let mut editor = editor_with_lines(&["a"]);
+        editor.delete_under_cursor();
+        assert_eq!(editor.lines[0].orig, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn join_next_line() {
+        let mut editor = editor_with_lines(&["ab", "\tc"]);
+        editor.cursor_to_byte(2);
+        editor.delete_under_cursor();
+        assert_eq!(editor.lines.len(), 1);
+        assert_eq!(editor.lines[0].orig, b"ab\tc".to_vec());
+        // The tab is re-expanded relative to its new position in the line.
+        assert_eq!(editor.lines[0].render, b"ab  c".to_vec());
+    }
+
+    #[test]
+    fn hard_wrap_long_lines() {
+        let mut editor = editor_with_lines(&["short", "one two three", "abcdefgh"]);
+        editor.config.max_line_length = Some(7);
+        editor.jump_to(1, 9);
+        editor.hard_wrap_long_lines();
+        let lines: Vec<&[u8]> = editor.lines.iter().map(|line| &line.orig[..]).collect();
+        assert_eq!(lines, vec![&b"short"[..], b"one two", b"three", b"abcdefg", b"h"]);
+        // The cursor stays on the "h" of "three".
+        assert_eq!((editor.cursor.line, editor.cursor.byte), (2, 1));
+        editor.undo();
+        assert_eq!(editor.lines.len(), 3);
+    }
 }